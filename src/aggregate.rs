@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use serde_json::{Map, Value};
+use crate::row::{Cell, Row, Schema};
+use crate::utils;
+
+/// VEP consequences, most severe first. See https://www.ensembl.org/info/genome/variation/prediction/predicted_data.html
+pub const CONSEQUENCE_RANKS: [&str; 41] = [
+    "transcript_ablation", "splice_acceptor_variant", "splice_donor_variant", "stop_gained",
+    "frameshift_variant", "stop_lost", "start_lost", "transcript_amplification", "feature_elongation",
+    "feature_truncation", "inframe_insertion", "inframe_deletion", "missense_variant",
+    "protein_altering_variant", "splice_donor_5th_base_variant", "splice_region_variant",
+    "splice_donor_region_variant", "splice_polypyrimidine_tract_variant", "incomplete_terminal_codon_variant",
+    "start_retained_variant", "stop_retained_variant", "synonymous_variant", "coding_sequence_variant",
+    "mature_miRNA_variant", "5_prime_UTR_variant", "3_prime_UTR_variant", "non_coding_transcript_exon_variant",
+    "intron_variant", "NMD_transcript_variant", "non_coding_transcript_variant", "coding_transcript_variant",
+    "upstream_gene_variant", "downstream_gene_variant", "TFBS_ablation", "TFBS_amplification",
+    "TF_binding_site_variant", "regulatory_region_ablation", "regulatory_region_amplification",
+    "regulatory_region_variant", "intergenic_variant", "sequence_variant",
+];
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct AggregateArgs {
+    /// column to group the filtered rows by, such as info.CSQ.SYMBOL
+    #[arg(long)]
+    pub by: String,
+
+    /// column holding the consequences to report the most severe of. Terms can be joined by &
+    #[arg(long, default_value = "info.CSQ.Consequence")]
+    pub consequence: String,
+
+    /// numeric columns to report the maximum of per group
+    #[arg(long, value_delimiter = ',')]
+    pub max: Vec<String>,
+
+    /// numeric columns to report the minimum of per group
+    #[arg(long, value_delimiter = ',')]
+    pub min: Vec<String>,
+
+    /// also report the number of qualifying variants each sample carries
+    #[arg(long, default_value_t = false)]
+    pub per_sample: bool,
+}
+
+impl AggregateArgs {
+    /// columns read from the filtered rows
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns = vec![self.by.to_string(), self.consequence.to_string(), "format.GT".to_string()];
+        columns.extend(self.max.iter().chain(self.min.iter()).cloned());
+        columns
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Group {
+    /// chromosome:position:reference:alternative of the qualifying variants
+    variants: HashSet<String>,
+    /// sample -> variants it carries
+    carriers: HashMap<String, HashSet<String>>,
+    /// index in CONSEQUENCE_RANKS of the most severe consequence, or the term if not in it
+    top_consequence: Option<(usize, String)>,
+    max: HashMap<String, Value>,
+    min: HashMap<String, Value>,
+}
+
+/// Per-group summary of the rows passing the filters, such as a gene burden table.
+/// Partial aggregates made on different threads are combined with `merge`.
+#[derive(Clone, Debug)]
+pub struct Aggregator {
+    args: AggregateArgs,
+    samples: Vec<String>,
+    groups: BTreeMap<String, Group>,
+}
+
+impl Aggregator {
+    pub fn new(args: AggregateArgs, samples: Vec<String>) -> Self {
+        Aggregator { args, samples, groups: BTreeMap::new() }
+    }
+
+    /// add a row passing the filters, whose columns are looked up in the schema of its input
+    pub fn add(&mut self, row: &Row, schema: &Schema) {
+        // values of a column, every element for a subfield of an unexploded field
+        let values = |name: &str| schema.get(row, name).map(Cell::elements).unwrap_or_default();
+        let variant = ["chromosome", "position", "reference", "alternative"].map(|x| schema.get(row, x).map(Cell::to_tsv).unwrap_or_default()).join(":");
+        let carriers = schema.sample_gt(row)
+            .filter(|(_, gt)| gt.and_then(utils::alt_allele_count).is_some_and(|(alt, _)| alt > 0))
+            .map(|(sample, _)| sample.to_string())
+            .collect::<Vec<String>>();
+        let consequences = values(&self.args.consequence).iter()
+            .filter_map(|x| x.as_str())
+            .flat_map(|x| x.split('&'))
+            .map(|x| (CONSEQUENCE_RANKS.iter().position(|c| *c == x).unwrap_or(CONSEQUENCE_RANKS.len()), x.to_string()))
+            .min();
+        // a row of an unexploded field can belong to several groups
+        let keys = values(&self.args.by).iter()
+            .filter_map(|x| match x {
+                Cell::Null => None,
+                Cell::Str(s) if s.is_empty() => None,
+                x => Some(x.to_tsv()),
+            })
+            .collect::<BTreeSet<String>>();
+        for key in keys {
+            let group = self.groups.entry(key).or_default();
+            group.variants.insert(variant.clone());
+            for sample in &carriers {
+                group.carriers.entry(sample.to_string()).or_default().insert(variant.clone());
+            }
+            if let Some(consequence) = &consequences {
+                if group.top_consequence.as_ref().is_none_or(|x| consequence < x) {
+                    group.top_consequence = Some(consequence.clone());
+                }
+            }
+            for column in &self.args.max {
+                for val in values(column) {
+                    update_extreme(&mut group.max, column, val, |x, y| x > y);
+                }
+            }
+            for column in &self.args.min {
+                for val in values(column) {
+                    update_extreme(&mut group.min, column, val, |x, y| x < y);
+                }
+            }
+        }
+    }
+
+    pub fn merge(mut self, other: Aggregator) -> Self {
+        for (key, other) in other.groups {
+            let group = self.groups.entry(key).or_default();
+            group.variants.extend(other.variants);
+            for (sample, variants) in other.carriers {
+                group.carriers.entry(sample).or_default().extend(variants);
+            }
+            if let Some(consequence) = other.top_consequence {
+                if group.top_consequence.as_ref().is_none_or(|x| consequence < *x) {
+                    group.top_consequence = Some(consequence);
+                }
+            }
+            for (column, val) in &other.max {
+                update_extreme(&mut group.max, column, &Cell::from_value(val), |x, y| x > y);
+            }
+            for (column, val) in &other.min {
+                update_extreme(&mut group.min, column, &Cell::from_value(val), |x, y| x < y);
+            }
+        }
+        self
+    }
+
+    /// output columns, in order
+    pub fn header(&self) -> Vec<String> {
+        let mut header = vec![self.args.by.to_string(), "n_variants".to_string(), "n_carriers".to_string(), "top_consequence".to_string()];
+        header.extend(self.args.max.iter().map(|x| format!("max.{}", x)));
+        header.extend(self.args.min.iter().map(|x| format!("min.{}", x)));
+        if self.args.per_sample {
+            header.extend(self.samples.iter().cloned());
+        }
+        header
+    }
+
+    /// one row per group, sorted by the group
+    pub fn rows(&self) -> Vec<Map<String, Value>> {
+        self.groups.iter().map(|(key, group)| {
+            let mut row = Map::new();
+            row.insert(self.args.by.to_string(), Value::String(key.to_string()));
+            row.insert("n_variants".to_string(), Value::from(group.variants.len()));
+            row.insert("n_carriers".to_string(), Value::from(group.carriers.len()));
+            row.insert("top_consequence".to_string(), group.top_consequence.as_ref().map(|(_, x)| Value::String(x.to_string())).unwrap_or(Value::Null));
+            for column in &self.args.max {
+                row.insert(format!("max.{}", column), group.max.get(column).cloned().unwrap_or(Value::Null));
+            }
+            for column in &self.args.min {
+                row.insert(format!("min.{}", column), group.min.get(column).cloned().unwrap_or(Value::Null));
+            }
+            if self.args.per_sample {
+                for sample in &self.samples {
+                    row.insert(sample.to_string(), Value::from(group.carriers.get(sample).map(|x| x.len()).unwrap_or(0)));
+                }
+            }
+            row
+        }).collect()
+    }
+}
+
+fn update_extreme(extremes: &mut HashMap<String, Value>, column: &str, val: &Cell, better: fn(f64, f64) -> bool) {
+    // keep the value as it is, so integers stay integers
+    let Some(x) = val.as_f64() else {
+        return;
+    };
+    match extremes.get(column).and_then(|x| x.as_f64()) {
+        Some(y) if !better(x, y) => (),
+        _ => {
+            extremes.insert(column.to_string(), val.to_value());
+        },
+    }
+}
@@ -0,0 +1,253 @@
+use std::io::{self, BufRead, Read, Write};
+use std::sync::Mutex;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Crc;
+use rayon::prelude::*;
+
+/// size of the fixed part of a gzip header, up to and including XLEN
+const HEADER_SIZE: usize = 12;
+/// batches of inflated blocks queued ahead of the parser
+const QUEUED_BATCHES: usize = 2;
+/// uncompressed data per block, as bgzip writes it, so a compressed block always fits in 64kb
+const BLOCK_DATA_SIZE: usize = 0xff00;
+/// the empty block that marks the end of a BGZF file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0, 0x1b, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// whether data starts with a BGZF block, a gzip member with a `BC` extra subfield holding its size
+pub fn is_bgzf(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE
+        && data[..4] == [0x1f, 0x8b, 8, 4]
+        && block_size(&data[HEADER_SIZE..data.len().min(HEADER_SIZE + xlen(data))]).is_some()
+}
+
+fn xlen(header: &[u8]) -> usize {
+    u16::from_le_bytes([header[10], header[11]]) as usize
+}
+
+/// total size of a block, from the `BC` subfield of its extra field
+fn block_size(extra: &[u8]) -> Option<usize> {
+    let mut extra = extra;
+    while extra.len() >= 4 {
+        let length = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        if extra[..2] == *b"BC" && length == 2 && extra.len() >= 6 {
+            return Some(u16::from_le_bytes([extra[4], extra[5]]) as usize + 1);
+        }
+        extra = &extra[(4 + length).min(extra.len())..];
+    }
+    None
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// read the next compressed block, or None at the end of the input
+fn read_block<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut block = vec![0; HEADER_SIZE];
+    let mut read = 0;
+    while read < HEADER_SIZE {
+        match reader.read(&mut block[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    if block[..4] != [0x1f, 0x8b, 8, 4] {
+        return Err(invalid("not a BGZF block"));
+    }
+    let extra_end = HEADER_SIZE + xlen(&block);
+    block.resize(extra_end, 0);
+    reader.read_exact(&mut block[HEADER_SIZE..])?;
+    let size = block_size(&block[HEADER_SIZE..]).ok_or_else(|| invalid("BGZF block without a size"))?;
+    if size < extra_end + 8 {
+        return Err(invalid("BGZF block too small"));
+    }
+    block.resize(size, 0);
+    reader.read_exact(&mut block[extra_end..])?;
+    Ok(Some(block))
+}
+
+/// decompress a block, checking its CRC and size
+fn inflate(block: &[u8]) -> io::Result<Vec<u8>> {
+    let footer = &block[block.len() - 8..];
+    let crc = u32::from_le_bytes([footer[0], footer[1], footer[2], footer[3]]);
+    let size = u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]) as usize;
+    let mut data = Vec::with_capacity(size);
+    DeflateDecoder::new(&block[HEADER_SIZE + xlen(block)..block.len() - 8]).read_to_end(&mut data)?;
+    let mut check = Crc::new();
+    check.update(&data);
+    if data.len() != size || check.sum() != crc {
+        return Err(invalid("BGZF block fails its CRC check"));
+    }
+    Ok(data)
+}
+
+/// Reads BGZF (bgzip) input, inflating blocks in parallel on a pool of its own.
+/// Blocks are read in batches on a background thread, and handed over in order
+pub struct BgzfReader {
+    batches: Mutex<Receiver<io::Result<Vec<u8>>>>,
+    batch: Vec<u8>,
+    position: usize,
+}
+
+impl BgzfReader {
+    /// `threads` inflate the blocks, 0 to use all available
+    pub fn new<R: Read + Send + 'static>(mut reader: R, threads: usize) -> io::Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().map_err(io::Error::other)?;
+        let batch_size = pool.current_num_threads() * 4;
+        let (sender, receiver) = sync_channel(QUEUED_BATCHES);
+        thread::spawn(move || loop {
+            let mut blocks = Vec::with_capacity(batch_size);
+            let mut read_batch = || -> io::Result<bool> {
+                while blocks.len() < batch_size {
+                    match read_block(&mut reader)? {
+                        Some(block) => blocks.push(block),
+                        None => return Ok(true),
+                    }
+                }
+                Ok(false)
+            };
+            let end = read_batch();
+            let batch = pool.install(|| blocks.par_iter().map(|x| inflate(x)).collect::<io::Result<Vec<Vec<u8>>>>())
+                .map(|x| x.concat());
+            let inflated = batch.is_ok();
+            // stop once the reader is dropped, on the first error, or at the end of the input.
+            // Blocks read before a read error are still handed over
+            if sender.send(batch).is_err() || !inflated {
+                return;
+            }
+            match end {
+                Ok(false) => (),
+                Ok(true) => return,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                },
+            }
+        });
+        Ok(BgzfReader { batches: Mutex::new(receiver), batch: Vec::new(), position: 0 })
+    }
+}
+
+impl Read for BgzfReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for BgzfReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // batches can be empty, e.g. the EOF block
+        while self.position == self.batch.len() {
+            match self.batches.get_mut().unwrap().recv() {
+                Ok(batch) => {
+                    self.batch = batch?;
+                    self.position = 0;
+                },
+                // the background thread is done
+                Err(_) => break,
+            }
+        }
+        Ok(&self.batch[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.batch.len());
+    }
+}
+
+/// compress data of at most `BLOCK_DATA_SIZE` into a block
+fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), flate2::Compression::default());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    let size = HEADER_SIZE + 6 + compressed.len() + 8;
+    let mut block = Vec::with_capacity(size);
+    block.extend_from_slice(&[0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0]);
+    block.extend_from_slice(&(size as u16 - 1).to_le_bytes());
+    block.extend_from_slice(&compressed);
+    let mut crc = Crc::new();
+    crc.update(data);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(block)
+}
+
+/// Writes BGZF, compressing batches of blocks in parallel on a pool of its own.
+/// Keeps where each block starts, so positions in the data can be turned into virtual offsets for an index
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    pool: rayon::ThreadPool,
+    buffer: Vec<u8>,
+    /// data compressed in one go
+    batch_size: usize,
+    /// compressed and uncompressed offsets of each block written
+    blocks: Vec<(u64, u64)>,
+    compressed: u64,
+    uncompressed: u64,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    /// `threads` compress the blocks, 0 to use all available
+    pub fn new(inner: W, threads: usize) -> io::Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().map_err(io::Error::other)?;
+        let batch_size = pool.current_num_threads() * 4 * BLOCK_DATA_SIZE;
+        Ok(BgzfWriter { inner, pool, buffer: Vec::with_capacity(batch_size), batch_size, blocks: Vec::new(), compressed: 0, uncompressed: 0 })
+    }
+
+    /// compress and write the buffer. Unless `all`, a partial block at the end is kept for more data
+    fn write_blocks(&mut self, all: bool) -> io::Result<()> {
+        let size = if all { self.buffer.len() } else { self.buffer.len() / BLOCK_DATA_SIZE * BLOCK_DATA_SIZE };
+        let data = &self.buffer[..size];
+        let blocks = self.pool.install(|| data.par_chunks(BLOCK_DATA_SIZE).map(deflate).collect::<io::Result<Vec<Vec<u8>>>>())?;
+        for (block, data) in blocks.iter().zip(data.chunks(BLOCK_DATA_SIZE)) {
+            self.inner.write_all(block)?;
+            self.blocks.push((self.compressed, self.uncompressed));
+            self.compressed += block.len() as u64;
+            self.uncompressed += data.len() as u64;
+        }
+        self.buffer.drain(..size);
+        Ok(())
+    }
+
+    /// the virtual offset of a position in the uncompressed data written so far, for BGZF indexes
+    pub fn virtual_offset(&self, position: u64) -> u64 {
+        // a position at the end of a block is the start of the next one
+        let ind = self.blocks.partition_point(|(_, start)| *start <= position);
+        match ind.checked_sub(1).map(|x| self.blocks[x]) {
+            Some((compressed, start)) if position - start < BLOCK_DATA_SIZE as u64 => compressed << 16 | (position - start),
+            _ => self.compressed << 16,
+        }
+    }
+
+    /// write the rest of the data and the end of file block
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_blocks(true)?;
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= self.batch_size {
+            self.write_blocks(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_blocks(true)?;
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use serde_json::{json, Map, Value};
+use vcf::VCFRecord;
+use crate::error::VcfParserError;
+use crate::utils;
+
+/// statistics computed per cohort or group, as `cohort.<stat>` or `cohort.<group>.<stat>`
+pub const STATS: [&str; 6] = ["AC", "AN", "AF", "n_het", "n_hom_alt", "call_rate"];
+
+/// Allele statistics computed from the genotypes of the samples, rather than taken from INFO
+#[derive(Debug, Clone, Default)]
+pub struct CohortStats {
+    /// group name -> samples, in the order of the mapping file
+    pub groups: Vec<(String, Vec<String>)>,
+}
+
+impl CohortStats {
+    /// read a sample to group mapping, one `sample group` pair per line
+    pub fn from_file(path: &str) -> Result<Self, VcfParserError> {
+        let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let cols = line.split_whitespace().collect::<Vec<&str>>();
+            if cols.len() < 2 {
+                return Err(VcfParserError::InvalidArgument(format!("group line should have a sample and a group: {}", line)));
+            }
+            match groups.iter_mut().find(|(group, _)| group == cols[1]) {
+                Some((_, samples)) => samples.push(cols[0].to_string()),
+                None => groups.push((cols[1].to_string(), vec![cols[0].to_string()])),
+            }
+        }
+        Ok(CohortStats { groups })
+    }
+
+    /// output columns, the whole cohort first, then each group
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns = STATS.iter().map(|x| format!("cohort.{}", x)).collect::<Vec<String>>();
+        for (group, _) in &self.groups {
+            columns.extend(STATS.iter().map(|x| format!("cohort.{}.{}", group, x)));
+        }
+        columns
+    }
+
+    /// add the statistics of a record to its site, over every sample of the VCF rather than the ones selected for output
+    pub fn annotate_record(&self, site: &mut Map<String, Value>, vcf_record: &VCFRecord) {
+        let genotype = vcf_record.header().samples().iter().map(|sample| {
+            let gt = vcf_record.genotype(sample, b"GT").and_then(|x| x.first()).map(|x| String::from_utf8_lossy(x).to_string());
+            (String::from_utf8_lossy(sample).to_string(), json!({"GT": gt}))
+        }).collect::<Map<String, Value>>();
+        self.annotate(site, &genotype);
+    }
+
+    /// add the statistics of a variant to its site, given its genotypes (sample -> FORMAT fields)
+    pub fn annotate(&self, site: &mut Map<String, Value>, genotype: &Map<String, Value>) {
+        let samples = genotype.keys().map(|x| x.as_str()).collect::<Vec<&str>>();
+        insert_stats(site, "cohort", &samples, genotype);
+        for (group, group_samples) in &self.groups {
+            let samples = group_samples.iter().map(|x| x.as_str()).filter(|x| genotype.contains_key(*x)).collect::<Vec<&str>>();
+            insert_stats(site, &format!("cohort.{}", group), &samples, genotype);
+        }
+    }
+}
+
+fn insert_stats(site: &mut Map<String, Value>, prefix: &str, samples: &[&str], genotype: &Map<String, Value>) {
+    // genotypes with a missing allele, such as ./. or 0/., count as not called
+    let (mut ac, mut an, mut n_het, mut n_hom_alt, mut called) = (0, 0, 0, 0, 0);
+    for sample in samples {
+        let gt = genotype.get(*sample).and_then(|x| x.get("GT")).and_then(|x| x.as_str());
+        let Some((alt, ploidy)) = gt.and_then(utils::alt_allele_count) else {
+            continue;
+        };
+        called += 1;
+        ac += alt;
+        an += ploidy;
+        if alt > 0 && alt < ploidy {
+            n_het += 1;
+        } else if alt > 0 {
+            n_hom_alt += 1;
+        }
+    }
+    let ratio = |x: usize, y: usize| if y == 0 { Value::Null } else { Value::from(x as f64 / y as f64) };
+    site.insert(format!("{}.AC", prefix), Value::from(ac));
+    site.insert(format!("{}.AN", prefix), Value::from(an));
+    site.insert(format!("{}.AF", prefix), ratio(ac, an));
+    site.insert(format!("{}.n_het", prefix), Value::from(n_het));
+    site.insert(format!("{}.n_hom_alt", prefix), Value::from(n_hom_alt));
+    site.insert(format!("{}.call_rate", prefix), ratio(called, samples.len()));
+}
@@ -0,0 +1,101 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use serde_json::{Map, Value};
+use crate::pedigree::{Pedigree, Phase};
+use crate::row::{Cell, Row, Schema};
+
+/// default column holding the gene of a row, used to group variants for compound heterozygotes
+pub const DEFAULT_GENE_COLUMN: &str = "info.CSQ.Gene";
+
+/// chromosome, position, reference and alternative
+type VariantKey = (String, u64, String, String);
+
+/// A row passing the filters, kept until every record is read
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub row: Row<'static>,
+    /// input of the row, whose schema it has
+    pub input: usize,
+    /// affected individuals heterozygous for the variant, with the parent it came from
+    pub hets: Vec<(String, Phase)>,
+}
+
+/// affected individuals heterozygous for a variant and the parent it came from, given the genotypes of the
+/// PED members in its record as given by `Pedigree::record_genotype`, so the output samples don't change the pairs
+pub fn het_calls(pedigree: &Pedigree, genotype: &Map<String, Value>) -> Vec<(String, Phase)> {
+    pedigree.affected()
+        .filter(|x| pedigree.is_het(x, genotype))
+        .map(|x| (x.id.to_string(), pedigree.phase(x, genotype)))
+        .collect()
+}
+
+fn variant_key(row: &Row, schema: &Schema) -> VariantKey {
+    let text = |name: &str| schema.get(row, name).and_then(|x| x.as_str()).unwrap_or_default().to_string();
+    let position = schema.get(row, "position").and_then(|x| x.as_f64()).unwrap_or_default() as u64;
+    (text("chromosome"), position, text("reference"), text("alternative"))
+}
+
+fn genes(row: &Row, gene_column: Option<usize>) -> Vec<String> {
+    // exploded rows have one gene, unexploded ones have the genes of every transcript
+    let mut genes = gene_column.and_then(|id| row.cells[id].as_ref()).map(Cell::elements).unwrap_or_default().iter()
+        .filter_map(|x| x.as_str())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>();
+    genes.sort_unstable();
+    genes.dedup();
+    genes
+}
+
+/// Find compound heterozygotes among the rows that passed the filters, whose columns are in `schemas` by input.
+/// For every affected individual, heterozygous variants are grouped by gene, and every two variants
+/// of a gene make a candidate pair unless the parents show they came from the same one.
+/// Rows in a pair get the pair ids in the `comphet_id` column, such as `KID:GENE1:1`.
+/// Rows not in any pair are dropped. Rows are returned sorted by position.
+pub fn annotate(rows: Vec<Candidate>, schemas: &[&Schema], gene_column: &str) -> Vec<Candidate> {
+    let gene_columns = schemas.iter().map(|x| x.id(gene_column)).collect::<Vec<Option<usize>>>();
+    let mut rows = rows.into_iter()
+        .map(|x| (variant_key(&x.row, schemas[x.input]), genes(&x.row, gene_columns[x.input]), x))
+        .collect::<Vec<(VariantKey, Vec<String>, Candidate)>>();
+    rows.sort_by(|x, y| x.0.cmp(&y.0));
+    // (sample, gene) -> distinct variants and their phase, in position order
+    let mut candidates: BTreeMap<(String, String), Vec<(&VariantKey, Phase)>> = BTreeMap::new();
+    for (key, genes, row) in &rows {
+        for (child, phase) in &row.hets {
+            for gene in genes {
+                let variants = candidates.entry((child.to_string(), gene.to_string())).or_default();
+                if variants.last().is_none_or(|(x, _)| *x != key) {
+                    variants.push((key, *phase));
+                }
+            }
+        }
+    }
+    // (variant, gene) -> pair ids
+    let mut pairs: HashMap<(&VariantKey, &str), Vec<String>> = HashMap::new();
+    for ((sample, gene), variants) in &candidates {
+        let mut count = 0;
+        for (ind, (first, first_phase)) in variants.iter().enumerate() {
+            for (second, second_phase) in &variants[ind + 1..] {
+                if *first_phase != Phase::Unknown && first_phase == second_phase {
+                    continue;
+                }
+                count += 1;
+                let id = format!("{}:{}:{}", sample, gene, count);
+                for key in [*first, *second] {
+                    pairs.entry((key, gene.as_str())).or_default().push(id.clone());
+                }
+            }
+        }
+    }
+    let ids = rows.iter().map(|(key, genes, _)| {
+        genes.iter().flat_map(|gene| pairs.get(&(key, gene.as_str())).cloned().unwrap_or_default()).collect::<Vec<String>>()
+    }).collect::<Vec<Vec<String>>>();
+    rows.into_iter().zip(ids).filter_map(|((_, _, mut row), ids)| {
+        if ids.is_empty() {
+            return None;
+        }
+        let ids = ids.into_iter().map(|x| Cell::Str(Cow::Owned(x))).collect();
+        schemas[row.input].set(&mut row.row, "comphet_id", Cell::List(ids));
+        Some(row)
+    }).collect()
+}
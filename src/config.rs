@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::ffi::OsString;
+use clap::{CommandFactory, FromArgMatches};
+use clap::parser::ValueSource;
+use serde_yaml::{Mapping, Value};
+use crate::error::VcfParserError;
+use crate::Args;
+
+/// parse the command line, taking the options it doesn't give from `--config`, and its `--preset` if any.
+///
+/// The config is a yaml file of options by their long name (`fields_join` or `fields-join`), with lists for options
+/// that take several values and mappings for ones taking `name=value`, such as `derive` and `rename`.
+/// Its `presets` section has named sets of options, which override the ones at the top of the file
+pub fn parse_args<I, T>(argv: I) -> Result<Args, Box<dyn Error>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let argv = argv.into_iter().map(|x| x.into()).collect::<Vec<OsString>>();
+    let matches = Args::command().get_matches_from(&argv);
+    let path = match matches.get_one::<String>("config") {
+        Some(path) => path.to_string(),
+        None => return Ok(Args::from_arg_matches(&matches)?),
+    };
+    let options = load_config(&path, matches.get_one::<String>("preset").map(|x| x.as_str()))?;
+    let command = Args::command();
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut config_args = Vec::new();
+    for (key, value) in options {
+        let key = key.as_str().ok_or_else(|| VcfParserError::InvalidArgument(format!("options in {} should be named by strings", path)))?;
+        let arg = command.get_arguments()
+            .find(|x| x.get_id() == key && x.get_long().is_some() && !["config", "preset", "help", "version"].contains(&x.get_id().as_str()))
+            .ok_or_else(|| VcfParserError::InvalidArgument(format!("{} in {} is not an option", key, path)))?;
+        // the command line wins, also over options it can't be given with, such as samples_file over samples
+        if given(key) || command.get_arg_conflicts_with(arg).iter().any(|x| given(x.get_id().as_str())) {
+            continue;
+        }
+        let long = format!("--{}", arg.get_long().unwrap_or_default());
+        for value in option_values(key, &value)? {
+            match value {
+                None => config_args.push(OsString::from(&long)),
+                Some(x) => config_args.push(OsString::from(format!("{}={}", long, x))),
+            }
+        }
+    }
+    // options from the config go first, so the subcommand and its arguments stay where they were given
+    let argv = argv[..1].iter().cloned().chain(config_args).chain(argv[1..].iter().cloned()).collect::<Vec<OsString>>();
+    Ok(Args::from_arg_matches(&Args::command().get_matches_from(argv))?)
+}
+
+/// the options of a config file, with those of `preset` on top
+fn load_config(path: &str, preset: Option<&str>) -> Result<Mapping, Box<dyn Error>> {
+    let file = std::fs::File::open(path)?;
+    let mut options = match serde_yaml::from_reader(file)? {
+        Value::Mapping(x) => normalise_keys(x),
+        Value::Null => Mapping::new(),
+        _ => return Err(VcfParserError::InvalidArgument(format!("{} should be a mapping of options to values", path)).into()),
+    };
+    let presets = match options.remove("presets") {
+        Some(Value::Mapping(x)) => x,
+        None | Some(Value::Null) => Mapping::new(),
+        Some(_) => return Err(VcfParserError::InvalidArgument(format!("presets in {} should be a mapping of names to options", path)).into()),
+    };
+    if let Some(preset) = preset {
+        let names = presets.keys().filter_map(|x| x.as_str()).collect::<Vec<&str>>();
+        match presets.get(preset) {
+            Some(Value::Mapping(x)) => options.extend(normalise_keys(x.clone())),
+            Some(Value::Null) => (),
+            Some(_) => return Err(VcfParserError::InvalidArgument(format!("preset {} should be a mapping of options to values", preset)).into()),
+            None => return Err(VcfParserError::InvalidArgument(format!("no preset {} in {}, available: {}", preset, path, names.join(", "))).into()),
+        }
+    }
+    Ok(options)
+}
+
+/// options named as `fields-join` are the same as `fields_join`
+fn normalise_keys(options: Mapping) -> Mapping {
+    options.into_iter().map(|(key, value)| match key {
+        Value::String(x) => (Value::String(x.replace('-', "_")), value),
+        x => (x, value),
+    }).collect()
+}
+
+/// values of an option, as given on the command line. None is a flag without a value
+fn option_values(key: &str, value: &Value) -> Result<Vec<Option<String>>, VcfParserError> {
+    let scalar = |x: &Value| match x {
+        Value::String(x) => Ok(x.to_string()),
+        Value::Number(x) => Ok(x.to_string()),
+        Value::Bool(x) => Ok(x.to_string()),
+        _ => Err(VcfParserError::InvalidArgument(format!("{} should be a value, a list or a mapping of values", key))),
+    };
+    match value {
+        Value::Null | Value::Bool(false) => Ok(vec![]),
+        Value::Bool(true) => Ok(vec![None]),
+        Value::Sequence(x) => x.iter().map(|x| scalar(x).map(Some)).collect(),
+        Value::Mapping(x) => x.iter().map(|(name, x)| Ok(Some(format!("{}={}", scalar(name)?, scalar(x)?)))).collect(),
+        x => Ok(vec![Some(scalar(x)?)]),
+    }
+}
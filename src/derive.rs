@@ -0,0 +1,249 @@
+use serde_json::{Number, Value};
+use crate::error::VcfParserError;
+use crate::parser;
+use crate::row::{Cell, Row, Schema};
+
+/// functions with their least and most number of arguments
+const FUNCTIONS: [(&str, usize, usize); 14] = [
+    ("abs", 1, 1), ("min", 1, usize::MAX), ("max", 1, usize::MAX), ("round", 1, 2), ("coalesce", 1, usize::MAX), ("if", 1, 3),
+    ("concat", 1, usize::MAX), ("lower", 1, 1), ("upper", 1, 1), ("len", 1, 1), ("substr", 2, 3), ("contains", 2, 2),
+    ("replace", 3, 3), ("split", 3, 3),
+];
+
+/// A column computed from other columns, such as `pangolin_abs = abs(info.Pangolin.pangolin_max_score)`
+#[derive(Debug, Clone)]
+pub struct DerivedColumn {
+    /// column name, usable in --columns and filters
+    pub name: String,
+    /// parsed expression, see `parser::parse_arith_expr`
+    pub expr: Value,
+}
+
+impl DerivedColumn {
+    pub fn new(name: &str, expression: &str) -> Result<Self, VcfParserError> {
+        let expr = parser::parse_arith_expr(expression)
+            .map_err(|e| VcfParserError::InvalidExpression(format!("{}: {}", name, e)))?;
+        check_functions(&expr).map_err(|e| VcfParserError::InvalidExpression(format!("{}: {}", name, e)))?;
+        Ok(DerivedColumn { name: name.trim().to_string(), expr })
+    }
+
+    /// parse a `name=expression` definition given on the command line
+    pub fn parse(definition: &str) -> Result<Self, VcfParserError> {
+        match definition.split_once('=') {
+            Some((name, expression)) if !name.trim().is_empty() => DerivedColumn::new(name, expression),
+            _ => Err(VcfParserError::InvalidExpression(format!("{} should look like name=expression", definition))),
+        }
+    }
+}
+
+fn check_functions(expr: &Value) -> Result<(), String> {
+    // functions should be known and operators and functions given the arguments they take, so evaluating can't fail
+    let args = match expr.get("args") {
+        Some(Value::Array(args)) => args.as_slice(),
+        _ => &[],
+    };
+    if let Some(f) = expr.get("fn").and_then(|x| x.as_str()) {
+        let (_, min, max) = FUNCTIONS.iter().find(|(x, ..)| *x == f).ok_or_else(|| format!("unknown function {}", f))?;
+        if args.len() < *min || args.len() > *max {
+            let expected = match (min, max) {
+                (min, max) if min == max => min.to_string(),
+                (min, &usize::MAX) => format!("at least {}", min),
+                (min, max) => format!("{} to {}", min, max),
+            };
+            return Err(format!("{} takes {} arguments, not {}", f, expected, args.len()));
+        }
+    }
+    if let Some(op) = expr.get("op").and_then(|x| x.as_str()) {
+        let expected = if op == "neg" { 1 } else { 2 };
+        if args.len() != expected {
+            return Err(format!("{} takes {} arguments, not {}", op, expected, args.len()));
+        }
+    }
+    for arg in args {
+        check_functions(arg)?;
+    }
+    Ok(())
+}
+
+/// columns an expression reads
+pub fn columns(expr: &Value) -> Vec<String> {
+    if let Some(name) = expr.get("col").and_then(|x| x.as_str()) {
+        return vec![name.to_string()];
+    }
+    match expr.get("args") {
+        Some(Value::Array(args)) => args.iter().flat_map(columns).collect(),
+        _ => vec![],
+    }
+}
+
+/// evaluate the derived columns in order and add them to the row, so later columns can use earlier ones
+pub fn add_row_columns(row: &mut Row, schema: &Schema, derived: &[DerivedColumn]) {
+    for column in derived {
+        let val = evaluate_with(&column.expr, &|name| schema.get(row, name).map(|x| x.to_value()).unwrap_or(Value::Null));
+        schema.set(row, &column.name, Cell::owned(val));
+    }
+}
+
+/// evaluate an expression, getting the values of columns from `column`
+pub fn evaluate_with(expr: &Value, column: &dyn Fn(&str) -> Value) -> Value {
+    // missing values propagate as null through arithmetic, and are skipped by min/max/coalesce
+    if let Some(val) = expr.get("lit") {
+        return val.clone();
+    }
+    if let Some(name) = expr.get("col").and_then(|x| x.as_str()) {
+        return column(name);
+    }
+    let args = match expr.get("args") {
+        Some(Value::Array(args)) => args.as_slice(),
+        _ => &[],
+    };
+    if let Some(op) = expr.get("op").and_then(|x| x.as_str()) {
+        let vals = args.iter().map(|x| evaluate_with(x, column)).collect::<Vec<Value>>();
+        return match op {
+            "neg" => arithmetic("-", &Value::from(0), &vals[0]),
+            "+" | "-" | "*" | "/" => arithmetic(op, &vals[0], &vals[1]),
+            _ => compare(op, &vals[0], &vals[1]),
+        };
+    }
+    match expr.get("fn").and_then(|x| x.as_str()) {
+        // only evaluate the branch that is taken
+        Some("if") => {
+            if truthy(&evaluate_with(&args[0], column)) {
+                args.get(1).map(|x| evaluate_with(x, column)).unwrap_or(Value::Null)
+            } else {
+                args.get(2).map(|x| evaluate_with(x, column)).unwrap_or(Value::Null)
+            }
+        },
+        Some(f) => {
+            let vals = args.iter().map(|x| evaluate_with(x, column)).collect::<Vec<Value>>();
+            function(f, &vals)
+        },
+        None => Value::Null,
+    }
+}
+
+fn float(x: f64) -> Value {
+    // NaN and infinity (e.g. division by zero) become null
+    Number::from_f64(x).map(Value::Number).unwrap_or(Value::Null)
+}
+
+fn arithmetic(op: &str, a: &Value, b: &Value) -> Value {
+    let (Value::Number(a), Value::Number(b)) = (a, b) else {
+        return Value::Null;
+    };
+    // stay with integers where possible, so e.g. info.AC + 1 is still an integer
+    if let (Some(x), Some(y)) = (a.as_i64(), b.as_i64()) {
+        let result = match op {
+            "+" => x.checked_add(y),
+            "-" => x.checked_sub(y),
+            "*" => x.checked_mul(y),
+            _ => None,
+        };
+        if let Some(result) = result {
+            return Value::from(result);
+        }
+    }
+    let (x, y) = (a.as_f64().unwrap(), b.as_f64().unwrap());
+    match op {
+        "+" => float(x + y),
+        "-" => float(x - y),
+        "*" => float(x * y),
+        _ => float(x / y),
+    }
+}
+
+fn compare(op: &str, a: &Value, b: &Value) -> Value {
+    let ordering = match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64().unwrap().partial_cmp(&y.as_f64().unwrap()),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        _ => None,
+    };
+    match (op, ordering) {
+        ("==" | "=", Some(o)) => Value::Bool(o.is_eq()),
+        ("==" | "=", None) => Value::Bool(a == b),
+        ("!=", Some(o)) => Value::Bool(o.is_ne()),
+        ("!=", None) => Value::Bool(a != b),
+        ("<", Some(o)) => Value::Bool(o.is_lt()),
+        ("<=", Some(o)) => Value::Bool(o.is_le()),
+        (">", Some(o)) => Value::Bool(o.is_gt()),
+        (">=", Some(o)) => Value::Bool(o.is_ge()),
+        _ => Value::Null,
+    }
+}
+
+fn truthy(val: &Value) -> bool {
+    match val {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().unwrap() != 0.0,
+        Value::String(s) => !s.is_empty(),
+        _ => true,
+    }
+}
+
+fn as_string(val: &Value) -> Option<String> {
+    match val {
+        Value::Null => None,
+        Value::String(s) => Some(s.to_string()),
+        x => Some(x.to_string()),
+    }
+}
+
+fn function(f: &str, vals: &[Value]) -> Value {
+    let arg = |ind: usize| vals.get(ind).unwrap_or(&Value::Null);
+    let int_arg = |ind: usize| arg(ind).as_i64();
+    match f {
+        "abs" => match arg(0) {
+            Value::Number(n) => n.as_i64().map(|x| Value::from(x.abs())).unwrap_or_else(|| float(n.as_f64().unwrap().abs())),
+            _ => Value::Null,
+        },
+        "min" | "max" => {
+            let numbers = vals.iter().filter(|x| x.is_number());
+            let best = if f == "min" {
+                numbers.min_by(|x, y| x.as_f64().unwrap().total_cmp(&y.as_f64().unwrap()))
+            } else {
+                numbers.max_by(|x, y| x.as_f64().unwrap().total_cmp(&y.as_f64().unwrap()))
+            };
+            best.cloned().unwrap_or(Value::Null)
+        },
+        "round" => match arg(0).as_f64() {
+            Some(x) => {
+                let factor = 10f64.powi(int_arg(1).unwrap_or(0) as i32);
+                float((x * factor).round() / factor)
+            },
+            None => Value::Null,
+        },
+        "coalesce" => vals.iter().find(|x| !x.is_null()).cloned().unwrap_or(Value::Null),
+        "concat" => Value::String(vals.iter().filter_map(as_string).collect::<Vec<String>>().concat()),
+        "lower" => as_string(arg(0)).map(|s| Value::String(s.to_lowercase())).unwrap_or(Value::Null),
+        "upper" => as_string(arg(0)).map(|s| Value::String(s.to_uppercase())).unwrap_or(Value::Null),
+        "len" => as_string(arg(0)).map(|s| Value::from(s.chars().count())).unwrap_or(Value::Null),
+        // substr(s, start[, length]), 0-based
+        "substr" => match (as_string(arg(0)), int_arg(1)) {
+            (Some(s), Some(start)) => {
+                let chars = s.chars().skip(start.max(0) as usize);
+                Value::String(match int_arg(2) {
+                    Some(length) => chars.take(length.max(0) as usize).collect(),
+                    None => chars.collect(),
+                })
+            },
+            _ => Value::Null,
+        },
+        "contains" => match (as_string(arg(0)), as_string(arg(1))) {
+            (Some(s), Some(sub)) => Value::Bool(s.contains(&sub)),
+            _ => Value::Null,
+        },
+        "replace" => match (as_string(arg(0)), as_string(arg(1)), as_string(arg(2))) {
+            (Some(s), Some(from), Some(to)) => Value::String(s.replace(&from, &to)),
+            _ => Value::Null,
+        },
+        // split(s, separator, index), e.g. split(info.CSQ.Consequence, "&", 0)
+        "split" => match (as_string(arg(0)), as_string(arg(1)), int_arg(2)) {
+            (Some(s), Some(sep), Some(ind)) => s.split(sep.as_str()).nth(ind.max(0) as usize)
+                .map(|x| Value::String(x.to_string()))
+                .unwrap_or(Value::Null),
+            _ => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
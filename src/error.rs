@@ -10,4 +10,8 @@ pub enum VcfParserError {
     InvalidArgument(String),
     #[error("Invalid filter expression: {0}")]
     InvalidFilter(String),
+    #[error("Invalid derived column: {0}")]
+    InvalidExpression(String),
+    #[error("Malformed record at line {line}: {message}")]
+    MalformedRecord { line: u64, message: String },
 }
\ No newline at end of file
@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use serde_json::Value;
+use crate::error::VcfParserError;
+use crate::lookup::{Intervals, KnownVariants, Lookups, BED_PREFIX, FILE_PREFIX};
+use crate::row::{Cell, Row, Schema};
+use crate::utils::{self, Region};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    In,
+}
+
+/// A filter tree compiled against a schema, so conditions look columns up by id rather than by name.
+/// The tree nests AND / OR lists of conditions, e.g.
+/// ```json
+/// {"AND": [
+///     {"name": "info.gnomAD_exome_AF", "op": "le", "value": 0.05},
+///     {"OR": [
+///         {"name": "info.CADD_PHRED", "op": "ge", "value": 20},
+///         {"name": "info.CSQ.LoF", "op": "ne", "value": null}
+///     ]}
+/// ]}
+/// ```
+#[derive(Debug, Clone)]
+pub enum Filter {
+    /// no filter, every row passes
+    True,
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Condition {
+        /// None if the column is not in the schema, so it is always null
+        column: Option<usize>,
+        op: Op,
+        value: Cell<'static>,
+        /// whether every element of a list has to match, rather than any
+        all: bool,
+        /// whether the column is a nested subfield, which is a list of the element values when not exploded
+        subfield: bool,
+    },
+    Overlaps(Vec<Region>),
+    /// a column in the values of a file, e.g. `info.CSQ.SYMBOL in @file:genes.txt`
+    InFile {
+        column: Option<usize>,
+        values: Arc<HashSet<String>>,
+        all: bool,
+        subfield: bool,
+    },
+    /// `variant in @file:known.tsv`, by chromosome, position, reference and alternative
+    KnownVariant(Arc<KnownVariants>),
+    /// `variant within @bed:panel.bed`, the interval of the variant (position to end) is inside one in the BED file
+    Within(Arc<Intervals>),
+}
+
+impl Filter {
+    /// `lookups` has the files of `@file:` and `@bed:` values
+    pub fn compile(filters: &Value, schema: &Schema, lookups: &Lookups) -> Result<Self, VcfParserError> {
+        let invalid = |x: &str| VcfParserError::InvalidFilter(x.to_string());
+        let map = match filters {
+            Value::Null => return Ok(Filter::True),
+            Value::Object(map) => map,
+            x => return Err(invalid(&format!("filter should be a mapping: {}", x))),
+        };
+        // a mapping is either a condition, or AND / OR with a list of filters
+        if let Some(name) = map.get("name") {
+            let name = name.as_str().ok_or_else(|| invalid("name of a condition should be a string"))?;
+            let op = map.get("op").and_then(|x| x.as_str()).ok_or_else(|| invalid(&format!("no operator for {}", name)))?;
+            let value = map.get("value").cloned().unwrap_or(Value::Null);
+            let all = map.get("match").and_then(|x| x.as_str()) == Some("all");
+            let column = schema.id(name);
+            let subfield = column.is_some_and(|x| schema.is_subfield(x));
+            match (op, value.as_str()) {
+                ("in" | "∈", Some(x)) if x.starts_with(FILE_PREFIX) => {
+                    let path = &x[FILE_PREFIX.len()..];
+                    return Ok(match name {
+                        "variant" => Filter::KnownVariant(lookups.variants(path)?),
+                        _ => Filter::InFile { column, values: lookups.values(path)?, all, subfield },
+                    });
+                },
+                ("within", Some(x)) if x.starts_with(BED_PREFIX) => return Ok(Filter::Within(lookups.intervals(&x[BED_PREFIX.len()..])?)),
+                ("within", _) => return Err(invalid(&format!("within should be given a BED file like @bed:panel.bed, not {}", value))),
+                (_, Some(x)) if x.starts_with(BED_PREFIX) => return Err(invalid(&format!("{} is only for `variant within`", x))),
+                _ => (),
+            }
+            let op = match op {
+                "eq" | "=" | "==" => Op::Eq,
+                "ne" | "!=" | "≠" => Op::Ne,
+                "gt" | ">" => Op::Gt,
+                "ge" | ">=" | "≥" => Op::Ge,
+                "lt" | "<" => Op::Lt,
+                "le" | "<=" | "≤" => Op::Le,
+                "in" | "∈" => Op::In,
+                "overlaps" => return utils::parse_regions(&value).map(Filter::Overlaps),
+                x => return Err(invalid(&format!("unknown operator {}", x))),
+            };
+            return Ok(Filter::Condition { column, op, value: Cell::owned(value), all, subfield });
+        }
+        match map.iter().next() {
+            Some((k, Value::Array(filters))) if k.eq_ignore_ascii_case("AND") || k.eq_ignore_ascii_case("OR") => {
+                let filters = filters.iter().map(|x| Filter::compile(x, schema, lookups)).collect::<Result<Vec<Filter>, VcfParserError>>()?;
+                Ok(if k.eq_ignore_ascii_case("AND") { Filter::And(filters) } else { Filter::Or(filters) })
+            },
+            Some((k, _)) => Err(invalid(&format!("{} should be AND or OR with a list of filters", k))),
+            None => Ok(Filter::Or(vec![])),
+        }
+    }
+
+    pub fn matches(&self, row: &Row, schema: &Schema) -> bool {
+        match self {
+            Filter::True => true,
+            Filter::And(filters) => filters.iter().all(|x| x.matches(row, schema)),
+            Filter::Or(filters) => filters.iter().any(|x| x.matches(row, schema)),
+            Filter::Overlaps(regions) => regions.iter().any(|x| schema.overlaps(row, x)),
+            Filter::Condition { column, op, value, all, subfield } => {
+                matches_cells(row, *column, *all, *subfield, |x| compare(x, *op, value))
+            },
+            Filter::InFile { column, values, all, subfield } => matches_cells(row, *column, *all, *subfield, |x| match x {
+                Cell::Null => false,
+                Cell::Str(x) => values.contains(x.as_ref()),
+                x => values.contains(&x.to_tsv()),
+            }),
+            Filter::KnownVariant(variants) => {
+                let text = |name: &str| schema.get(row, name).and_then(|x| x.as_str()).unwrap_or_default();
+                let position = schema.get(row, "position").and_then(|x| x.as_f64()).unwrap_or_default() as u64;
+                variants.contains(text("chromosome"), position, text("reference"), text("alternative"))
+            },
+            Filter::Within(intervals) => {
+                let chromosome = schema.get(row, "chromosome").and_then(|x| x.as_str()).unwrap_or_default();
+                let number = |name: &str| schema.get(row, name).and_then(|x| x.as_f64()).map(|x| x as u64);
+                let start = number("position").unwrap_or_default();
+                intervals.contains(chromosome, start, number("end").unwrap_or(start).max(start))
+            },
+        }
+    }
+}
+
+/// whether the cell of `column` matches, or any or `all` of its elements for lists such as unexploded subfields
+fn matches_cells(row: &Row, column: Option<usize>, all: bool, subfield: bool, matches: impl Fn(&Cell) -> bool) -> bool {
+    let cell = column.and_then(|x| row.cells[x].as_ref()).unwrap_or(&Cell::Null);
+    let vals = match cell {
+        Cell::List(x) if subfield || !x.is_empty() => x.iter().collect::<Vec<&Cell>>(),
+        x => vec![x],
+    };
+    if all {
+        vals.into_iter().all(matches)
+    } else {
+        vals.into_iter().any(matches)
+    }
+}
+
+fn compare(val: &Cell, op: Op, value: &Cell) -> bool {
+    let numbers = || val.as_f64().zip(value.as_f64());
+    match op {
+        Op::Eq => val.equals(value),
+        Op::Ne => !val.equals(value),
+        Op::Gt => numbers().is_some_and(|(x, y)| x > y),
+        Op::Ge => numbers().is_some_and(|(x, y)| x >= y),
+        Op::Lt => numbers().is_some_and(|(x, y)| x < y),
+        Op::Le => numbers().is_some_and(|(x, y)| x <= y),
+        Op::In => match value {
+            Cell::List(arr) => arr.iter().any(|x| val.equals(x)),
+            x => val.equals(x),
+        },
+    }
+}
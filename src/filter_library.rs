@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use serde_json::{json, Map, Value};
+use crate::derive::DerivedColumn;
+use crate::error::VcfParserError;
+use crate::parser;
+use crate::utils;
+
+/// Named filters from filter yaml files, which can refer to each other by name and include other files:
+/// ```yaml
+/// include: [common.yml]
+/// filters:
+///   rare: info.AF <= 0.01
+///   damaging:
+///     OR:
+///       - info.CSQ.IMPACT == HIGH
+///       - info.CADD_PHRED >= 20
+///   rare_damaging: rare AND damaging
+/// ```
+/// A filter is an expression or a filter tree, whose conditions can also be expressions.
+/// Files can also have a `derive` section of computed columns, and a `filter` to use when the file is given to -f
+#[derive(Debug, Default)]
+pub struct FilterLibrary {
+    filters: HashMap<String, Value>,
+    /// derived columns of every file, in the order they were loaded
+    pub derived: Vec<DerivedColumn>,
+    /// files loaded so far, so the filters of a file included twice are only added once
+    loaded: HashSet<PathBuf>,
+}
+
+fn invalid(message: String) -> VcfParserError {
+    VcfParserError::InvalidFilter(message)
+}
+
+impl FilterLibrary {
+    /// add the filters and derived columns of a yaml file and the files it includes,
+    /// and return its `filter`, or the whole file if it is a filter tree
+    pub fn load(&mut self, path: &str) -> Result<Value, Box<dyn Error>> {
+        let canonical = std::fs::canonicalize(path).map_err(|e| VcfParserError::InvalidArgument(format!("can't read {}: {}", path, e)))?;
+        let first = self.loaded.insert(canonical);
+        let yaml: serde_yaml::Value = serde_yaml::from_reader(std::fs::File::open(path)?)?;
+        let sections = ["include", "filters", "derive", "filter"];
+        if !sections.iter().any(|x| yaml.get(x).is_some()) {
+            return Ok(serde_yaml::from_value(yaml)?);
+        }
+        if first {
+            self.add_sections(path, &yaml)?;
+        }
+        match yaml.get("filter") {
+            Some(filter) => Ok(serde_yaml::from_value(filter.clone())?),
+            None => Ok(Value::Null),
+        }
+    }
+
+    fn add_sections(&mut self, path: &str, yaml: &serde_yaml::Value) -> Result<(), Box<dyn Error>> {
+        // included files are relative to the file including them
+        let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        let includes = match yaml.get("include") {
+            None => vec![],
+            Some(serde_yaml::Value::String(x)) => vec![x.to_string()],
+            Some(serde_yaml::Value::Sequence(x)) => x.iter().map(|x| x.as_str().map(String::from))
+                .collect::<Option<Vec<String>>>().ok_or_else(|| invalid(format!("include in {} should be a list of files", path)))?,
+            Some(_) => return Err(invalid(format!("include in {} should be a list of files", path)).into()),
+        };
+        for include in includes {
+            self.load(&directory.join(include).to_string_lossy())?;
+        }
+        match yaml.get("derive") {
+            // a yaml mapping keeps its order, so derived columns can use the ones defined before them
+            Some(serde_yaml::Value::Mapping(derive)) => for (name, expression) in derive {
+                let name = name.as_str().ok_or("derived column names should be strings")?;
+                let expression = match expression {
+                    serde_yaml::Value::String(x) => x.to_string(),
+                    x => serde_yaml::to_string(x)?.trim().to_string(),
+                };
+                self.derived.push(DerivedColumn::new(name, &expression)?);
+            },
+            Some(_) => return Err("derive should be a mapping of column names to expressions".into()),
+            None => (),
+        }
+        match yaml.get("filters") {
+            // filters of a file replace the ones of the same name it includes
+            Some(serde_yaml::Value::Mapping(filters)) => for (name, filter) in filters {
+                let name = name.as_str().ok_or_else(|| invalid(format!("filter names in {} should be strings", path)))?;
+                self.filters.insert(name.to_string(), serde_yaml::from_value(filter.clone())?);
+            },
+            Some(_) => return Err(invalid(format!("filters in {} should be a mapping of names to filters", path)).into()),
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// the filter tree of a named filter
+    pub fn get(&self, name: &str) -> Result<Value, VcfParserError> {
+        self.resolve(&json!({"ref": name}))
+    }
+
+    /// turn the expressions of a filter into trees, and the names of filters into their trees
+    pub fn resolve(&self, filter: &Value) -> Result<Value, VcfParserError> {
+        self.resolve_inner(filter, &mut Vec::new())
+    }
+
+    /// `names` are the filters being resolved, to report filters referring to themselves
+    fn resolve_inner(&self, filter: &Value, names: &mut Vec<String>) -> Result<Value, VcfParserError> {
+        match filter {
+            Value::String(expression) => {
+                let tree = parser::parse_logic_expr(expression).map_err(|e| invalid(format!("{}: {}", expression, e)))?;
+                self.resolve_inner(&tree, names)
+            },
+            Value::Object(map) if map.contains_key("ref") => {
+                let name = map["ref"].as_str().ok_or_else(|| invalid(format!("filter names should be strings: {}", map["ref"])))?;
+                let filter = self.filters.get(name).ok_or_else(|| {
+                    let mut available = self.filters.keys().map(|x| x.as_str()).collect::<Vec<&str>>();
+                    available.sort();
+                    invalid(format!("unknown filter {}. Filters available: {}", name, available.join(", ")))
+                })?;
+                if names.iter().any(|x| x == name) {
+                    return Err(invalid(format!("filter {} refers to itself: {} -> {}", name, names.join(" -> "), name)));
+                }
+                names.push(name.to_string());
+                let tree = self.resolve_inner(filter, names)?;
+                names.pop();
+                Ok(tree)
+            },
+            Value::Object(map) => {
+                // regions are checked here rather than for every record
+                if map.get("op").is_some_and(|x| x == "overlaps") {
+                    utils::parse_regions(map.get("value").unwrap_or(&Value::Null))?;
+                }
+                let mut resolved = Map::new();
+                for (key, value) in map {
+                    let value = match value {
+                        Value::Array(x) if key.eq_ignore_ascii_case("AND") || key.eq_ignore_ascii_case("OR") => {
+                            Value::Array(x.iter().map(|x| self.resolve_inner(x, names)).collect::<Result<Vec<Value>, VcfParserError>>()?)
+                        },
+                        x => x.clone(),
+                    };
+                    resolved.insert(key.to_string(), value);
+                }
+                Ok(Value::Object(resolved))
+            },
+            x => Ok(x.clone()),
+        }
+    }
+}
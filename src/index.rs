@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use crate::bgzf::BgzfWriter;
+use crate::error::VcfParserError;
+
+/// size of the smallest bins and of the windows of the linear index, as 1 << MIN_SHIFT
+const MIN_SHIFT: u32 = 14;
+/// levels of bins in a tabix index, which covers positions up to 2^29
+const TBI_DEPTH: u32 = 5;
+/// levels of bins in a CSI index, which covers positions up to 2^32
+const CSI_DEPTH: u32 = 6;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum IndexFormat {
+    /// tabix .tbi, for positions up to 2^29
+    Tbi,
+    /// .csi, for longer chromosomes
+    Csi,
+}
+
+impl IndexFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            IndexFormat::Tbi => "tbi",
+            IndexFormat::Csi => "csi",
+        }
+    }
+
+    fn depth(&self) -> u32 {
+        match self {
+            IndexFormat::Tbi => TBI_DEPTH,
+            IndexFormat::Csi => CSI_DEPTH,
+        }
+    }
+}
+
+/// Columns of a tab separated file to index, as tabix takes them
+#[derive(Clone, Debug)]
+pub struct IndexColumns {
+    /// 1-based column of the chromosome
+    pub chromosome: usize,
+    /// 1-based column of the 1-based position
+    pub position: usize,
+    /// 1-based column of the end position (inclusive), 0 if none
+    pub end: usize,
+    /// header lines to skip
+    pub skip: usize,
+}
+
+#[derive(Default)]
+struct Reference {
+    /// bin -> chunks of records in it, as uncompressed offsets
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    /// uncompressed offset of the first record overlapping each window
+    linear: Vec<Option<u64>>,
+}
+
+/// the bin of a 0-based, half open interval, as hts_reg2bin
+fn region_to_bin(beg: u64, end: u64, depth: u32) -> u32 {
+    let end = end.max(beg + 1) - 1;
+    let mut shift = MIN_SHIFT;
+    let mut first = ((1u64 << (3 * depth)) - 1) / 7;
+    for level in (1..=depth).rev() {
+        if beg >> shift == end >> shift {
+            return (first + (beg >> shift)) as u32;
+        }
+        shift += 3;
+        first -= 1 << (3 * (level - 1));
+    }
+    0
+}
+
+/// the first position of a bin
+fn bin_start(bin: u32, depth: u32) -> u64 {
+    let mut first = 0u64;
+    for level in 0..=depth {
+        let next = first + (1 << (3 * level));
+        if (bin as u64) < next {
+            return (bin as u64 - first) << (MIN_SHIFT + 3 * (depth - level));
+        }
+        first = next;
+    }
+    0
+}
+
+/// Builds a tabix or CSI index of a sorted, BGZF compressed tab separated file as it is written
+pub struct Indexer {
+    format: IndexFormat,
+    columns: IndexColumns,
+    names: Vec<String>,
+    references: Vec<Reference>,
+    /// position of the last record, to check the records are sorted
+    last: Option<u64>,
+    /// header lines seen so far
+    skipped: usize,
+}
+
+impl Indexer {
+    pub fn new(format: IndexFormat, columns: IndexColumns) -> Self {
+        Indexer { format, columns, names: Vec::new(), references: Vec::new(), last: None, skipped: 0 }
+    }
+
+    /// add a line written at uncompressed offsets `start` to `end`
+    pub fn add(&mut self, line: &str, start: u64, end: u64) -> Result<(), VcfParserError> {
+        if self.skipped < self.columns.skip {
+            self.skipped += 1;
+            return Ok(());
+        }
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        let field = |column: usize| fields.get(column - 1).copied().unwrap_or_default();
+        let invalid = || VcfParserError::InvalidArgument(format!("can't index a row without chromosome and position: {}", line));
+        let chromosome = field(self.columns.chromosome);
+        let position = field(self.columns.position).parse::<u64>().map_err(|_| invalid())?;
+        if chromosome.is_empty() || position == 0 {
+            return Err(invalid());
+        }
+        // intervals are 0-based and half open, and cover at least the position
+        let beg = position - 1;
+        let stop = match self.columns.end {
+            0 => position,
+            column => field(column).parse::<u64>().unwrap_or(position).max(position),
+        };
+        if stop > 1 << (MIN_SHIFT + 3 * self.format.depth()) {
+            return Err(VcfParserError::InvalidArgument(format!("position {} is too large for a {} index, use csi", stop, self.format.extension())));
+        }
+        if self.names.last().map(|x| x.as_str()) != Some(chromosome) {
+            if self.names.iter().any(|x| x == chromosome) {
+                return Err(VcfParserError::InvalidArgument(format!("output has to be sorted to be indexed, but {} is not contiguous", chromosome)));
+            }
+            self.names.push(chromosome.to_string());
+            self.references.push(Reference::default());
+            self.last = None;
+        }
+        if self.last.is_some_and(|x| position < x) {
+            return Err(VcfParserError::InvalidArgument(format!("output has to be sorted to be indexed, but {0}:{1} comes after {0}:{2}", chromosome, position, self.last.unwrap_or_default())));
+        }
+        self.last = Some(position);
+        let reference = self.references.last_mut().unwrap();
+        let chunks = reference.bins.entry(region_to_bin(beg, stop, self.format.depth())).or_default();
+        // records written one after the other share a chunk
+        match chunks.last_mut() {
+            Some(chunk) if chunk.1 == start => chunk.1 = end,
+            _ => chunks.push((start, end)),
+        }
+        let last_window = ((stop - 1) >> MIN_SHIFT) as usize;
+        if reference.linear.len() <= last_window {
+            reference.linear.resize(last_window + 1, None);
+        }
+        for window in &mut reference.linear[(beg >> MIN_SHIFT) as usize..=last_window] {
+            window.get_or_insert(start);
+        }
+        Ok(())
+    }
+
+    /// write the index to `path`, given the virtual offsets of uncompressed offsets in the indexed file
+    pub fn write(&self, path: &str, virtual_offset: &dyn Fn(u64) -> u64) -> io::Result<()> {
+        let mut out = BgzfWriter::new(BufWriter::new(File::create(path)?), 1)?;
+        let int = |out: &mut BgzfWriter<BufWriter<File>>, x: usize| out.write_all(&(x as i32).to_le_bytes());
+        // the tabix header: generic format, the columns, `#` for comment lines, and the reference names
+        let mut header = Vec::new();
+        let names = self.names.iter().flat_map(|x| x.bytes().chain([0])).collect::<Vec<u8>>();
+        for x in [0, self.columns.chromosome, self.columns.position, self.columns.end, b'#' as usize, self.columns.skip, names.len()] {
+            header.extend_from_slice(&(x as i32).to_le_bytes());
+        }
+        header.extend_from_slice(&names);
+        match self.format {
+            IndexFormat::Tbi => {
+                out.write_all(b"TBI\x01")?;
+                int(&mut out, self.names.len())?;
+                out.write_all(&header)?;
+            },
+            IndexFormat::Csi => {
+                out.write_all(b"CSI\x01")?;
+                int(&mut out, MIN_SHIFT as usize)?;
+                int(&mut out, CSI_DEPTH as usize)?;
+                int(&mut out, header.len())?;
+                out.write_all(&header)?;
+                int(&mut out, self.names.len())?;
+            },
+        }
+        for reference in &self.references {
+            // windows without records point to the previous one
+            let mut linear = Vec::with_capacity(reference.linear.len());
+            let mut previous = 0;
+            for window in &reference.linear {
+                previous = window.map(virtual_offset).unwrap_or(previous);
+                linear.push(previous);
+            }
+            int(&mut out, reference.bins.len())?;
+            for (bin, chunks) in &reference.bins {
+                out.write_all(&bin.to_le_bytes())?;
+                if self.format == IndexFormat::Csi {
+                    // the first record overlapping the start of the bin
+                    let window = (bin_start(*bin, CSI_DEPTH) >> MIN_SHIFT) as usize;
+                    out.write_all(&linear.get(window).copied().unwrap_or_default().to_le_bytes())?;
+                }
+                int(&mut out, chunks.len())?;
+                for (start, end) in chunks {
+                    out.write_all(&virtual_offset(*start).to_le_bytes())?;
+                    out.write_all(&virtual_offset(*end).to_le_bytes())?;
+                }
+            }
+            if self.format == IndexFormat::Tbi {
+                int(&mut out, linear.len())?;
+                for offset in linear {
+                    out.write_all(&offset.to_le_bytes())?;
+                }
+            }
+        }
+        out.finish()
+    }
+}
@@ -3,8 +3,9 @@
 use flate2::read::MultiGzDecoder;
 use clap::Parser;
 use rayon::prelude::*;
-use vcfparser::VcfParser;
+use vcfparser::{SampleOptions, VcfParser, VcfParserOptions};
 use std::{fs::File, error::Error, str, path::Path};
+use std::collections::HashMap;
 use std::io::{self, BufRead, BufReader};
 pub use vcf::VCFRecord;
 use crate::variant::Variant;
@@ -17,111 +18,614 @@ pub mod variant;
 pub mod utils;
 pub mod error;
 pub mod parser;
+pub mod derive;
+pub mod pedigree;
+pub mod comphet;
+pub mod aggregate;
+pub mod cohort;
+pub mod stats;
+pub mod row;
+pub mod filter;
+pub mod filter_library;
+pub mod lookup;
+pub mod bgzf;
+pub mod index;
+pub mod output;
+pub mod schema;
+pub mod config;
 
 #[derive(Parser)]
 #[command(version = "0.3.0", about = "Read a (normalised) .vcf[.gz] and output tsv/json. CSQ-aware.", long_about = None)]
 #[command(styles=get_styles())]
 pub struct Args {
-    /// input .vcf[.gz] file, or ignore to read from stdin
-    #[arg(short, long, value_parser = vcf_extension_validator)]
-    input: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// threads to use, default to use all available
-    #[arg(short, long, default_value_t = 0)]
+    /// input .vcf[.gz] file, or ignore to read from stdin. Can be repeated, e.g. for per-chromosome files, which are read one after another
+    #[arg(short, long, value_parser = vcf_extension_validator, global = true)]
+    input: Vec<String>,
+
+    /// file of input VCFs, one per line, read after the ones given by -i
+    #[arg(long, global = true)]
+    input_list: Option<String>,
+
+    /// yaml file of options, such as fields, columns, renames and filters. Options given on the command line override it
+    #[arg(long, global = true)]
+    config: Option<String>,
+
+    /// named set of options from the `presets` section of --config, e.g. rare_disease
+    #[arg(long, requires = "config", global = true)]
+    preset: Option<String>,
+
+    /// threads to use for parsing, and for decompressing BGZF input. With BGZF input a quarter of them
+    /// (at least one) decompress and the rest parse. Default to use all available
+    #[arg(short, long, default_value_t = 0, global = true)]
     threads: usize,
 
-    /// filter expression (experimental) or yaml file to use
-    #[arg(short, long)]
+    /// filter expression (experimental), yaml file to use, or @name of a filter in --filter-library
+    #[arg(short, long, global = true)]
     filter: Option<String>,
 
+    /// yaml file of named filters, which -f and filter expressions can refer to. Can be repeated
+    #[arg(long, global = true)]
+    filter_library: Vec<String>,
+
+    /// only output variants overlapping these regions, such as chr1:100-200. SVs overlap by their whole interval
+    #[arg(short, long, value_delimiter = ',', global = true)]
+    region: Vec<String>,
+
+    /// computed column as name=expression, e.g. pangolin_abs=abs(info.Pangolin.pangolin_max_score). Can be repeated
+    #[arg(long, global = true)]
+    derive: Vec<String>,
+
     /// list of columns available for query/output
-    #[arg(short, long, default_value_t = false)]
+    #[arg(short, long, default_value_t = false, global = true)]
     list: bool,
 
-    /// nested fields with | separator to parse, such as CSQ
-    #[arg(long, value_parser, value_delimiter = ',', default_value = "CSQ")]
+    /// nested fields with | separator to parse, such as CSQ. Use `auto` to discover them from the header
+    #[arg(long, value_parser, value_delimiter = ',', default_value = "CSQ", global = true)]
     fields: Vec<String>,
 
-    /// nested fields join together on e.g. transcript id, such as Feature
-    #[arg(long, value_parser, value_delimiter = ',', default_value = "Feature")]
-    fields_join: Vec<String>,
+    /// nested fields join together on e.g. transcript id [default: Feature]. Use + for composite keys, e.g. Gene+Feature.
+    /// With `--fields auto`, a transcript-like subfield of each field is used if not given
+    #[arg(long, value_parser, value_delimiter = ',', global = true)]
+    fields_join: Option<Vec<String>>,
+
+    /// how to join nested fields. left keeps every row of the first field.
+    /// Rows sharing a key are joined with every row of that key in the other field
+    #[arg(long, default_value_t, value_enum, global = true)]
+    join_type: JoinType,
 
-    /// specify output columns
-    #[arg(short, long, value_delimiter = ',')]
+    /// ignore version numbers when joining, e.g. ENST00000392370.5 matches ENST00000392370
+    /// Off by default, while earlier versions always ignored them
+    #[arg(long, default_value_t = false, global = true)]
+    join_ignore_version: bool,
+
+    /// specify output columns, in this order. Globs are expanded in header order, e.g. info.CSQ.*,info.gnomAD_*_AF
+    #[arg(short, long, value_delimiter = ',', global = true)]
     columns: Option<Vec<String>>,
 
+    /// columns to leave out of the output, which can be globs
+    #[arg(long, value_delimiter = ',', global = true)]
+    exclude_columns: Vec<String>,
+
+    /// output a column under another name, as column=name (e.g. info.CSQ.SYMBOL=gene), or a yaml file mapping columns to names. Filters and -c take either name
+    #[arg(long, value_delimiter = ',', global = true)]
+    rename: Vec<String>,
+
+    /// output INFO columns without `info.` and their field, where the name stays unambiguous, e.g. SYMBOL for info.CSQ.SYMBOL
+    #[arg(long, default_value_t = false, global = true)]
+    strip_prefix: bool,
+
     /// output format.
-    #[arg(long, default_value_t, value_enum)]
+    #[arg(long, default_value_t, value_enum, global = true)]
     output_format: OutputFormat,
+
+    /// file to write to instead of stdout. Compressed by its extension: .gz or .bgz for BGZF, .zst for zstd
+    #[arg(short, long, global = true)]
+    output: Option<String>,
+
+    /// compression of the output, instead of the one from its extension
+    #[arg(long, value_enum, global = true)]
+    compress: Option<output::Compression>,
+
+    /// write a .tbi (default) or .csi index next to the output. Needs BGZF tsv output with the chromosome and position columns, from a sorted VCF
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "tbi", global = true)]
+    index: Option<index::IndexFormat>,
+
+    /// samples to output, in this order. Genotypes of the other samples are not parsed
+    #[arg(long, value_delimiter = ',', global = true)]
+    samples: Option<Vec<String>>,
+
+    /// file of samples to output, one per line
+    #[arg(long, conflicts_with = "samples", global = true)]
+    samples_file: Option<String>,
+
+    /// samples to leave out
+    #[arg(long, value_delimiter = ',', global = true)]
+    exclude_samples: Vec<String>,
+
+    /// tab separated file of VCF sample names and the names to output them as. Renamed samples are referred to by their new names, e.g. in --ped
+    #[arg(long, global = true)]
+    rename_samples: Option<String>,
+
+    /// PED file of the families in the VCF, to annotate the inheritance models each variant fits
+    #[arg(long, global = true)]
+    ped: Option<String>,
+
+    /// minimum GQ of a parent's genotype to be trusted for inheritance models
+    #[arg(long, default_value_t = 20, global = true)]
+    parent_min_gq: i64,
+
+    /// minimum DP of a parent's genotype to be trusted for inheritance models
+    #[arg(long, default_value_t = 0, global = true)]
+    parent_min_dp: i64,
+
+    /// only output compound heterozygote candidates of the affected individuals in --ped, paired within each gene
+    #[arg(long, global = true)]
+    comphet: bool,
+
+    /// column holding the gene to pair compound heterozygotes in, e.g. info.CSQ.SYMBOL
+    #[arg(long, default_value_t = comphet::DEFAULT_GENE_COLUMN.to_string(), global = true)]
+    comphet_gene: String,
+
+    /// compute AC, AN, AF, n_het, n_hom_alt and call_rate from the genotypes, as cohort.* columns usable in filters.
+    /// They count every sample of the VCF, not only the ones output with --samples
+    #[arg(long, default_value_t = false, global = true)]
+    compute_cohort_stats: bool,
+
+    /// file mapping samples to groups, one `sample group` per line by their VCF names, to also compute cohort.<group>.* columns
+    #[arg(long, global = true)]
+    cohort_groups: Option<String>,
+
+    /// output one json object per variant, keeping nested fields as arrays instead of exploding them into rows
+    #[arg(long, default_value_t = false, global = true)]
+    no_explode: bool,
+
+    /// with --no-explode, whether a filter condition must hold for any or all elements of a nested field
+    #[arg(long, default_value_t, value_enum, global = true)]
+    array_match: ArrayMatch,
 }
 
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// summarise the filtered rows per group, such as a gene burden table
+    Aggregate(aggregate::AggregateArgs),
+    /// QC report of the filtered records: counts per chromosome, FILTER, variant type and consequence, Ti/Tv, per-sample genotypes and histograms
+    Stats(stats::StatsArgs),
+    /// describe the output columns: where each comes from, its VCF Type, Number and Description, and the type of the values of nested subfields
+    Schema(schema::SchemaArgs),
+}
+
+
 
+/// the parser of one input
+type InputParser = VcfParser<Box<dyn BufRead + Send + Sync>>;
+/// takes the rows of a record passing the filters, with the parser of their input
+type RowSink<'s> = dyn FnMut(&[&row::Row], &InputParser) -> Result<(), error::VcfParserError> + 's;
+
+/// records processed at a time when the output has to keep the order of the VCF
+const ORDERED_CHUNK_LINES: usize = 10000;
 
 pub fn run(args:Args)-> Result<(), Box<dyn Error>> {
+    let command = args.command;
+    // named filters, and the derived columns they use
+    let mut library = filter_library::FilterLibrary::default();
+    for path in &args.filter_library {
+        library.load(path)?;
+    }
     // read filter if given
-    // if space is present, treat it as a logic expression
+    // @name is a filter of the library. if space is present, treat it as a logic expression
     // otherwise, treat it as a file
-    let filters: serde_json::Value = if let Some(filter_string) = args.filter  {
-        if filter_string.contains(" ") {
-            parser::parse_logic_expr(&filter_string).map_err(|e| error::VcfParserError::InvalidFilter(e.to_string()))?
-        } else {
-            let filter_file = File::open(filter_string)?;
-            serde_yaml::from_reader(filter_file)?
-        }
-    } else {
-        serde_json::Value::Null
+    let mut filters = match &args.filter {
+        Some(filter_string) => match filter_string.strip_prefix('@') {
+            Some(name) => library.get(name)?,
+            None if filter_string.contains(" ") => {
+                let filter = parser::parse_logic_expr(filter_string).map_err(|e| error::VcfParserError::InvalidFilter(e.to_string()))?;
+                library.resolve(&filter)?
+            },
+            None => {
+                let filter = library.load(filter_string)?;
+                library.resolve(&filter)?
+            },
+        },
+        None => serde_json::Value::Null,
     };
+    let mut derived = library.derived;
+    // derived columns from the command line come after the ones in the filter file
+    for definition in &args.derive {
+        derived.push(derive::DerivedColumn::parse(definition)?);
+    }
     
-    let reader: Box<dyn BufRead + Send + Sync> = match args.input {
-        None => Box::new(BufReader::new(io::stdin())),
-        Some(inp) if inp.ends_with(".vcf.gz") => Box::new(BufReader::new(MultiGzDecoder::new(File::open(inp)?))),
-        Some(rest) => Box::new(BufReader::new(File::open(rest)?))
+    if args.no_explode {
+        if args.output_format != OutputFormat::J {
+            return Err(error::VcfParserError::InvalidArgument("--no-explode only supports json output".into()).into());
+        }
+        if args.array_match == ArrayMatch::All {
+            utils::set_array_match(&mut filters, "all");
+        }
+    } else if args.array_match == ArrayMatch::All {
+        // exploded rows have one value per subfield, so there are no arrays to match
+        return Err(error::VcfParserError::InvalidArgument("--array-match all needs --no-explode".into()).into());
+    }
+    let explode = !args.no_explode;
+    let regions = args.region.iter().map(|x| utils::Region::parse(x)).collect::<Result<Vec<utils::Region>, Box<dyn Error>>>()?;
+
+    let mut inputs = args.input;
+    if let Some(path) = &args.input_list {
+        for input in utils::load_input_list(path)? {
+            inputs.push(vcf_extension_validator(&input).map_err(error::VcfParserError::InvalidArgument)?);
+        }
+    }
+    let auto_fields = args.fields.len() == 1 && args.fields[0] == vcfparser::AUTO_FIELDS;
+    let pedigree = match &args.ped {
+        Some(ped) => Some(pedigree::Pedigree::from_file(ped, args.parent_min_gq, args.parent_min_dp)?),
+        None => None,
+    };
+    let cohort = match &args.cohort_groups {
+        Some(groups) => Some(cohort::CohortStats::from_file(groups)?),
+        None if args.compute_cohort_stats => Some(cohort::CohortStats::default()),
+        None => None,
+    };
+    let samples = SampleOptions {
+        include: match &args.samples_file {
+            Some(path) => Some(utils::load_sample_list(path)?),
+            None => args.samples,
+        },
+        exclude: args.exclude_samples,
+        rename: match &args.rename_samples {
+            Some(path) => utils::load_sample_names(path)?,
+            None => HashMap::new(),
+        },
     };
-    let vcf_parser = VcfParser::new(filters, args.fields, args.fields_join, args.columns, args.output_format, reader)?;
+    let mut rename_columns = Vec::new();
+    for rename in &args.rename {
+        match rename.split_once('=') {
+            Some((column, name)) => rename_columns.push((column.to_string(), name.to_string())),
+            None => rename_columns.extend(utils::load_rename_file(rename)?),
+        }
+    }
+    let options = VcfParserOptions {
+        join: utils::JoinOptions { join_type: args.join_type, ignore_version: args.join_ignore_version },
+        derived,
+        pedigree,
+        comphet: args.comphet.then_some(args.comphet_gene),
+        cohort,
+        samples,
+        output_columns: match &command {
+            Some(Command::Aggregate(aggregate_args)) => Some(aggregate_args.columns()),
+            Some(Command::Stats(stats_args)) => Some(stats_args.columns()),
+            // the schema describes the output columns
+            Some(Command::Schema(_)) | None => None,
+        },
+        source_file: None,
+        exclude_columns: args.exclude_columns,
+        rename_columns: rename_columns.clone(),
+        strip_prefix: args.strip_prefix,
+        lookups: lookup::Lookups::default(),
+    };
+    if inputs.is_empty() {
+        inputs.push("-".to_string());
+    }
+    // one parser per input, as their headers can differ
+    let mut parsers = Vec::new();
+    for input in &inputs {
+        let options = VcfParserOptions { source_file: (inputs.len() > 1).then(|| input.to_string()), ..options.clone() };
+        let reader = open_header(input)?;
+        let vcf_parser = VcfParser::new(filters.clone(), args.fields.clone(), args.fields_join.clone(), options, args.columns.clone(), args.output_format.clone(), reader)?;
+        parsers.push(vcf_parser);
+    }
+    // renamed columns of every input
+    let mut output_names = HashMap::new();
+    for vcf_parser in &parsers {
+        for (column, name) in &vcf_parser.output_names {
+            output_names.entry(column.to_string()).or_insert_with(|| name.to_string());
+        }
+    }
+    // the output has the columns of every input. Selected columns are put back in their order,
+    // and each has to be in one of the inputs
+    let tsv_header = utils::union_headers(&parsers.iter().map(|x| &x.tsv_headers).collect::<Vec<&Vec<String>>>());
+    if parsers.len() > 1 {
+        if let Some((column, _)) = rename_columns.iter().find(|(x, _)| !output_names.contains_key(x)) {
+            return Err(error::VcfParserError::InvalidArgument(format!("Column {} is not in the header of any input", column)).into());
+        }
+        // inputs can rename different columns to the same name
+        let renamed = output_names.iter().filter(|(x, _)| tsv_header.contains(x)).map(|(x, y)| (x.to_string(), y.to_string())).collect::<Vec<(String, String)>>();
+        utils::output_names(&tsv_header, &renamed, false, true)?;
+    }
+    let tsv_header = match &args.columns {
+        Some(columns) if parsers.len() > 1 => {
+            let renamed = output_names.iter().map(|(column, name)| (name, column)).collect::<HashMap<&String, &String>>();
+            let columns = columns.iter().map(|x| renamed.get(x).map(|x| x.to_string()).unwrap_or(x.to_string())).collect::<Vec<String>>();
+            utils::select_columns(&tsv_header, Some(&columns), &[], true)?
+        },
+        _ => tsv_header,
+    };
+    if parsers.len() > 1 {
+        parsers.iter_mut().for_each(|x| x.set_output_columns(&tsv_header));
+    }
+    let output_header = tsv_header.iter().map(|x| output_names.get(x).unwrap_or(x).to_string()).collect::<Vec<String>>();
 
     // if --list, print the headers and quit
     if args.list {
-        let header = vcf_parser.tsv_headers;
-        utils::print_line_to_stdout(&header.join("\n"))?;
+        if auto_fields {
+            // report the discovered nested fields as comment lines before the columns
+            let mut listed = Vec::new();
+            for vcf_parser in &parsers {
+                for (ind, field) in vcf_parser.info_fields.iter().enumerate() {
+                    let subfields = &vcf_parser.csq_headers[field.trim_start_matches("info.")];
+                    let line = format!("# {} (join on {}): {}", field, vcf_parser.fields_join[ind].join("+"), subfields.join("|"));
+                    if !listed.contains(&line) {
+                        utils::print_line_to_stdout(&line)?;
+                        listed.push(line);
+                    }
+                }
+            }
+        }
+        utils::print_line_to_stdout(&output_header.join("\n"))?;
         return Ok(());
     }
-    // write tsv header to stdout
-    let tsv_header = vcf_parser.tsv_headers;
-    if vcf_parser.output_format == OutputFormat::T {
-        
-        utils::print_line_to_stdout(&tsv_header.join("\t"))?;
+    let output_format = parsers[0].output_format.clone();
+    // --index is checked before the output file is created, so an invalid one leaves no file behind
+    let index = match args.index {
+        Some(format) => {
+            let path = args.output.as_ref().ok_or_else(|| error::VcfParserError::InvalidArgument("--index needs --output".into()))?;
+            if command.is_some() || output_format != OutputFormat::T {
+                return Err(error::VcfParserError::InvalidArgument("only tsv output of variants can be indexed".into()).into());
+            }
+            if output::Compression::of_output(Some(path), args.compress) != output::Compression::Bgzf {
+                return Err(error::VcfParserError::InvalidArgument("only BGZF output can be indexed, e.g. --output out.tsv.gz".into()).into());
+            }
+            // tabix columns are 1-based, the header line is skipped
+            let column = |name: &str| tsv_header.iter().position(|x| x == name).map(|x| x + 1);
+            let columns = index::IndexColumns {
+                chromosome: column("chromosome").ok_or_else(|| error::VcfParserError::InvalidArgument("indexing needs the chromosome column".into()))?,
+                position: column("position").ok_or_else(|| error::VcfParserError::InvalidArgument("indexing needs the position column".into()))?,
+                end: column("end").unwrap_or(0),
+                skip: 1,
+            };
+            Some((format, columns, format!("{}.{}", path, format.extension())))
+        },
+        None => None,
+    };
+    let mut output = output::Output::new(args.output.as_deref(), args.compress, args.threads)?;
+    if let Some((format, columns, path)) = index {
+        output.index(format, columns, &path)?;
     }
-    
     // parallel processing each variant/site
-    
-    vcf_parser.reader.reader.lines().par_bridge().for_each(|line| {
-        let line = line.unwrap();
-        if line.starts_with("#") {
-            return;
-        }
-        let line = line.as_bytes() as &[u8];
-        let vcf_record = VCFRecord::from_bytes(line, 1, (*vcf_parser.header).clone()).unwrap();
-        let variant = Variant::new(&vcf_record, vcf_parser.header.samples(), &vcf_parser.csq_headers);
-        let explodeds = vcf_parser.info_fields.iter().map(|x| utils::explode_data(serde_json::to_value(&variant).unwrap(), x, &vcf_parser.info_fields)).collect::<Vec<Vec<Map<String, Value>>>>();
-        let joined = utils::outer_join(explodeds, &vcf_parser.fields_join).unwrap();
-        joined.iter().filter(|x| utils::filter_record(x, &vcf_parser.filters)).for_each(|x| {
-            match vcf_parser.output_format {
-                OutputFormat::T => {
-                    let row = utils::get_row(&x, &tsv_header);
-                    utils::print_line_to_stdout(&row.join("\t")).unwrap();
-                },
-                OutputFormat::J => {
-                    let j = serde_json::to_string(&x).unwrap();
-                    utils::print_line_to_stdout(&j).unwrap();
-                },
-                OutputFormat::V => {
-                    unimplemented!();
+    let bgzf_input = inputs.iter().any(|x| is_bgzf_input(x));
+    let (parse_threads, threads) = split_threads(args.threads, bgzf_input);
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(parse_threads).build()?;
+
+    // inputs are read one after another, each with its records sampled by `--fields auto` first.
+    // Lines are numbered as in their file, for errors
+    let lines = parsers.iter_mut().enumerate().map(|(input, vcf_parser)| {
+        let first_line = vcf_parser.first_line;
+        let sampled_lines = std::mem::take(&mut vcf_parser.sampled_lines);
+        let reader = std::mem::replace(&mut vcf_parser.reader.reader, vcfparser::LineCounter::new(Box::new(io::empty())));
+        let records: Box<dyn Iterator<Item = io::Result<String>> + Send> = match inputs[input].as_str() {
+            // stdin can't be opened again, so it goes on from its header
+            "-" => Box::new(reader.lines()),
+            // a file is opened again when its turn comes, so only the input being read has BGZF threads
+            path => {
+                let (path, read) = (path.to_string(), first_line - 1 + sampled_lines.len() as u64);
+                Box::new(std::iter::once(()).flat_map(move |_| match open_input(&path, threads) {
+                    Ok(reader) => Box::new(reader.lines().skip(read as usize)) as Box<dyn Iterator<Item = io::Result<String>> + Send>,
+                    Err(e) => Box::new(std::iter::once(Err(io::Error::other(e.to_string())))),
+                }))
+            },
+        };
+        sampled_lines.into_iter().map(Ok).chain(records)
+            .enumerate()
+            .map(move |(ind, line)| (input, first_line + ind as u64, line))
+    }).collect::<Vec<_>>().into_iter().flatten();
+    let parsers = &parsers;
+    let vcf_parser = &parsers[0];
+    // each thread parses into a record per input, which shares its header
+    let new_record = || parsers.iter().map(|x| VCFRecord::new(x.header.clone())).collect::<Vec<VCFRecord>>();
+    // rows borrow from the variant, so the passing rows of a record are handed to `sink` with the parser of their input
+    // rather than returned
+    let process = |records: &mut Vec<VCFRecord>, (input, line_number, line): (usize, u64, io::Result<String>), sink: &mut RowSink| -> Result<(), error::VcfParserError> {
+        let line = line?;
+        if line.is_empty() || line.starts_with("#") {
+            return Ok(());
+        }
+        let vcf_parser = &parsers[input];
+        let schema = &vcf_parser.schema;
+        let vcf_record = &mut records[input];
+        vcfparser::parse_record(vcf_record, &line, line_number)?;
+        let mut variant = Variant::with_projection(vcf_record, &vcf_parser.samples, &vcf_parser.csq_headers, vcf_parser.projection.as_ref());
+        variant.rename_samples(&vcf_parser.sample_names);
+        let mut annotations = Map::new();
+        if let Some(cohort) = &vcf_parser.cohort {
+            cohort.annotate_record(&mut annotations, vcf_record);
+        }
+        if let Some(pedigree) = &vcf_parser.pedigree {
+            let genotype = pedigree.record_genotype(vcf_record, &vcf_parser.sample_names);
+            annotations.insert("inheritance".to_string(), pedigree.inheritance(&variant.chromosome, &genotype).into());
+        }
+        if let Some(source_file) = &vcf_parser.source_file {
+            annotations.insert(vcfparser::SOURCE_FILE_COLUMN.to_string(), source_file.as_str().into());
+        }
+        let site = schema.row(&variant, &annotations);
+        // SVs are in a region if their interval overlaps it
+        if !regions.is_empty() && !regions.iter().any(|x| schema.overlaps(&site, x)) {
+            return Ok(());
+        }
+        let mut joined = if vcf_parser.info_fields.is_empty() || !explode {
+            // nothing to explode, one row per variant
+            vec![site]
+        } else {
+            row::join_tables(schema.explode(&site, &vcf_parser.info_fields), &vcf_parser.join_keys, &vcf_parser.join_options)
+        };
+        if !vcf_parser.derived.is_empty() {
+            joined.iter_mut().for_each(|x| derive::add_row_columns(x, schema, &vcf_parser.derived));
+        }
+        let passed = joined.iter().filter(|x| vcf_parser.compiled_filter.matches(x, schema)).collect::<Vec<&row::Row>>();
+        if passed.is_empty() {
+            return Ok(());
+        }
+        sink(&passed, vcf_parser)
+    };
+    if let Some(Command::Aggregate(aggregate_args)) = command {
+        let samples = vcf_parser.samples.iter()
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .map(|x| vcf_parser.sample_names.get(&x).cloned().unwrap_or(x))
+            .collect::<Vec<String>>();
+        let aggregator = aggregate::Aggregator::new(aggregate_args, samples);
+        let aggregator = pool.install(|| lines.par_bridge()
+            .try_fold(|| (aggregator.clone(), new_record()), |(mut acc, mut records), line| {
+                process(&mut records, line, &mut |rows, vcf_parser| {
+                    rows.iter().for_each(|x| acc.add(x, &vcf_parser.schema));
+                    Ok(())
+                })?;
+                Ok::<_, error::VcfParserError>((acc, records))
+            })
+            .map(|x| x.map(|(acc, _)| acc))
+            .try_reduce(|| aggregator.clone(), |a, b| Ok(a.merge(b))))?;
+        let header = aggregator.header();
+        if output_format == OutputFormat::T {
+            output.write_line(&header.join("\t"))?;
+        }
+        for row in aggregator.rows() {
+            match output_format {
+                OutputFormat::T => output.write_line(&utils::get_row(&row, &header).join("\t"))?,
+                _ => output.write_line(&serde_json::to_string(&row)?)?,
+            }
+        }
+        output.finish()?;
+        return Ok(());
+    }
+    if let Some(Command::Stats(stats_args)) = command {
+        let fields = utils::union_headers(&parsers.iter().map(|x| &x.info_fields).collect::<Vec<&Vec<String>>>());
+        let stats = stats::Stats::new(&stats_args, &fields).map_err(error::VcfParserError::InvalidArgument)?;
+        let stats = pool.install(|| lines.par_bridge()
+            .try_fold(|| (stats.clone(), new_record()), |(mut acc, mut records), line| {
+                process(&mut records, line, &mut |rows, vcf_parser| {
+                    acc.add(rows, &vcf_parser.schema);
+                    Ok(())
+                })?;
+                Ok::<_, error::VcfParserError>((acc, records))
+            })
+            .map(|x| x.map(|(acc, _)| acc))
+            .try_reduce(|| stats.clone(), |a, b| Ok(a.merge(b))))?;
+        match output_format {
+            OutputFormat::T => {
+                output.write_line("section\tkey\tvalue")?;
+                for row in stats.to_rows() {
+                    output.write_line(&row.join("\t"))?;
                 }
+            },
+            _ => output.write_line(&serde_json::to_string(&stats.to_json())?)?,
+        }
+        output.finish()?;
+        return Ok(());
+    }
+    if let Some(Command::Schema(schema_args)) = command {
+        // types of the columns without one in the header come from the first records
+        let mut inferred = schema::InferredTypes::new(&tsv_header);
+        let mut records = new_record();
+        for line in lines.take(schema_args.sample_records) {
+            process(&mut records, line, &mut |rows, vcf_parser| {
+                rows.iter().for_each(|x| inferred.add(x, &vcf_parser.schema));
+                Ok(())
+            })?;
+        }
+        let columns = tsv_header.iter().zip(&output_header).enumerate().map(|(ind, (column, name))| {
+            let vcf_parser = parsers.iter().find(|x| x.tsv_headers.contains(column)).unwrap_or(vcf_parser);
+            let derived = vcf_parser.derived.iter().map(|x| x.name.to_string()).collect::<Vec<String>>();
+            let mut info = schema::ColumnInfo::new(column, name, &vcf_parser.header, &vcf_parser.schema, &derived);
+            if info.value_type.is_none() {
+                info.inferred_type = inferred.get(ind);
             }
-        });
-        
-        
-    });
+            info
+        }).collect::<Vec<schema::ColumnInfo>>();
+        match output_format {
+            OutputFormat::T => {
+                output.write_line(&schema::HEADER.join("\t"))?;
+                for column in &columns {
+                    output.write_line(&column.to_tsv().join("\t"))?;
+                }
+            },
+            _ => output.write_line(&serde_json::to_string(&columns.iter().map(|x| x.to_json()).collect::<Vec<Value>>())?)?,
+        }
+        output.finish()?;
+        return Ok(());
+    }
+    // write tsv header
+    if output_format == OutputFormat::T {
+        output.write_line(&output_header.join("\t"))?;
+    }
+    // json is only made at the output
+    let format_row = |x: &row::Row, vcf_parser: &InputParser| -> String {
+        match output_format {
+            OutputFormat::T => vcf_parser.schema.tsv(x, &vcf_parser.tsv_columns).join("\t"),
+            OutputFormat::J if !output_names.is_empty() => serde_json::to_string(&utils::rename_keys(vcf_parser.schema.to_map(x), &output_names)).unwrap(),
+            OutputFormat::J => serde_json::to_string(&vcf_parser.schema.to_map(x)).unwrap(),
+            OutputFormat::V => {
+                unimplemented!();
+            }
+        }
+    };
+    match (&vcf_parser.comphet, &vcf_parser.pedigree) {
+        (Some(gene_column), Some(pedigree)) => {
+            // pairs span records, so collect every passing row before grouping them by gene.
+            // Rows are phased with the genotypes of every PED member in their record, not just the output samples
+            let rows = pool.install(|| lines.par_bridge()
+                .map_init(new_record, |records, line| {
+                    let input = line.0;
+                    let mut rows = Vec::new();
+                    process(records, line, &mut |x, _| {
+                        rows.extend(x.iter().map(|x| (*x).clone().into_static()));
+                        Ok(())
+                    })?;
+                    if rows.is_empty() {
+                        return Ok(vec![]);
+                    }
+                    let hets = comphet::het_calls(pedigree, &pedigree.record_genotype(&records[input], &parsers[input].sample_names));
+                    Ok(rows.into_iter().map(|row| comphet::Candidate { row, input, hets: hets.clone() }).collect())
+                })
+                .collect::<Result<Vec<Vec<comphet::Candidate>>, error::VcfParserError>>())?;
+            let schemas = parsers.iter().map(|x| &x.schema).collect::<Vec<&row::Schema>>();
+            for x in comphet::annotate(rows.into_iter().flatten().collect(), &schemas, gene_column) {
+                output.write_line(&format_row(&x.row, &parsers[x.input]))?;
+            }
+        },
+        _ if output.is_indexed() => {
+            // an index needs the rows in the order of the VCF, so chunks of records are processed in parallel and written in order
+            let mut lines = lines;
+            loop {
+                let chunk = lines.by_ref().take(ORDERED_CHUNK_LINES).collect::<Vec<(usize, u64, io::Result<String>)>>();
+                if chunk.is_empty() {
+                    break;
+                }
+                let rows = pool.install(|| chunk.into_par_iter()
+                    .map_init(new_record, |records, line| {
+                        let mut rows = Vec::new();
+                        process(records, line, &mut |x, vcf_parser| {
+                            rows.extend(x.iter().map(|x| format_row(x, vcf_parser)));
+                            Ok(())
+                        })?;
+                        Ok(rows)
+                    })
+                    .collect::<Result<Vec<Vec<String>>, error::VcfParserError>>())?;
+                for row in rows.iter().flatten() {
+                    output.write_line(row)?;
+                }
+            }
+        },
+        _ => {
+            // each worker formats rows into batches, which one thread writes
+            let writer = output.spawn(pool.current_num_threads() * 2);
+            let result = pool.install(|| lines.par_bridge().try_for_each_init(|| (new_record(), writer.batch()), |(records, batch), line| {
+                process(records, line, &mut |x, vcf_parser| x.iter().try_for_each(|x| batch.write_line(&format_row(x, vcf_parser))))
+            }));
+            // an error of the writer comes first, as it stops the workers
+            output = writer.join()?;
+            result?;
+        },
+    }
+    output.finish()?;
     Ok(())
 }
 
@@ -140,6 +644,82 @@ pub enum OutputFormat {
     V,
 }
 
+#[derive(
+    clap::ValueEnum, Clone, Copy, Default, Debug, Serialize,
+)]
+#[derive(PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum JoinType {
+    /// only rows present in every field
+    Inner,
+    /// all rows of the first field
+    Left,
+    /// all rows of every field
+    #[default]
+    Outer,
+}
+
+#[derive(
+    clap::ValueEnum, Clone, Copy, Default, Debug, Serialize,
+)]
+#[derive(PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArrayMatch {
+    /// at least one element matches
+    #[default]
+    Any,
+    /// every element matches
+    All,
+}
+
+/// open an input to read its header, and the records sampled by `--fields auto`.
+/// BGZF is inflated as plain gzip here, so the threads of `open_input` only start when the records are read
+fn open_header(path: &str) -> Result<Box<dyn BufRead + Send + Sync>, Box<dyn Error>> {
+    if path == "-" {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    let file = BufReader::new(File::open(path)?);
+    if path.ends_with(".gz") || path.ends_with(".bgz") {
+        return Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))));
+    }
+    Ok(Box::new(file))
+}
+
+/// whether an input is a bgzip file, inflated on threads of its own
+fn is_bgzf_input(path: &str) -> bool {
+    (path.ends_with(".gz") || path.ends_with(".bgz"))
+        && File::open(path).map(BufReader::new).is_ok_and(|mut x| x.fill_buf().is_ok_and(bgzf::is_bgzf))
+}
+
+/// split `--threads` into the threads parsing and the threads inflating BGZF input, 0 being all available
+fn split_threads(threads: usize, bgzf_input: bool) -> (usize, usize) {
+    let threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |x| x.get()),
+        x => x,
+    };
+    if !bgzf_input {
+        return (threads, 0);
+    }
+    let inflate = (threads / 4).max(1);
+    ((threads - inflate).max(1), inflate)
+}
+
+/// a reader of a .vcf, .vcf.gz or .vcf.bgz file
+fn open_input(path: &str, threads: usize) -> Result<Box<dyn BufRead + Send + Sync>, Box<dyn Error>> {
+    if path == "-" {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    let mut file = BufReader::new(File::open(path)?);
+    if path.ends_with(".gz") || path.ends_with(".bgz") {
+        // bgzip output is inflated in parallel, plain gzip is not splittable
+        if bgzf::is_bgzf(file.fill_buf()?) {
+            return Ok(Box::new(bgzf::BgzfReader::new(file, threads)?));
+        }
+        return Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))));
+    }
+    Ok(Box::new(file))
+}
+
 fn vcf_extension_validator(fname: &str) -> Result<String, String> {
     if fname == "-" {
         return Ok(format!("{fname}"));
@@ -150,7 +730,7 @@ fn vcf_extension_validator(fname: &str) -> Result<String, String> {
         ));
     }
     let file_extension = Path::new(&fname).extension().unwrap().to_str().unwrap();
-    if ["vcf", "gz"].contains(&file_extension) {
+    if ["vcf", "gz", "bgz"].contains(&file_extension) {
         // if return a &str from the input it won't compile
         if !Path::new(fname).exists() {
             return Err(format!("input file {fname} does not exist"));
@@ -234,6 +814,51 @@ mod tests {
         Ok((reader, csq_headers, filter))
     }
 
+    /// a schema of the columns of json tables, in the order they appear, and the tables as typed rows
+    /// json rows as typed rows, with the samples of `genotype` as sample columns
+    fn typed_rows(tables: &[Vec<Map<String, Value>>]) -> (row::Schema, Vec<Vec<row::Row<'static>>>) {
+        let mut columns: Vec<String> = Vec::new();
+        let mut samples: Vec<String> = Vec::new();
+        let add = |names: &mut Vec<String>, key: &String| if !names.contains(key) {
+            names.push(key.to_string());
+        };
+        for (key, val) in tables.iter().flatten().flatten() {
+            match (key.as_str(), val) {
+                ("genotype", Value::Object(genotype)) => genotype.keys().for_each(|x| add(&mut samples, x)),
+                _ => add(&mut columns, key),
+            }
+        }
+        let schema = row::Schema::new(&columns, &HashMap::new(), &samples);
+        let rows = tables.iter().map(|x| x.iter().map(|x| typed_row(&schema, x)).collect()).collect();
+        (schema, rows)
+    }
+
+    fn typed_row(schema: &row::Schema, record: &Map<String, Value>) -> row::Row<'static> {
+        let mut row = row::Row { cells: vec![None; schema.len()], exploded: true };
+        for (key, val) in record {
+            match (key.as_str(), val) {
+                ("genotype", Value::Object(genotype)) => genotype.iter().for_each(|(sample, x)| schema.set(&mut row, sample, row::Cell::owned(x.clone()))),
+                _ => schema.set(&mut row, key, row::Cell::owned(val.clone())),
+            }
+        }
+        row
+    }
+
+    /// join json tables as typed rows on key columns, and return the joined rows as json
+    fn join_rows(tables: &[Vec<Map<String, Value>>], keys: &[&[&str]], options: &utils::JoinOptions) -> Vec<Map<String, Value>> {
+        let (schema, tables) = typed_rows(tables);
+        let keys = keys.iter().map(|x| x.iter().map(|x| schema.id(x).unwrap()).collect()).collect::<Vec<Vec<usize>>>();
+        row::join_tables(tables, &keys, options).iter().map(|x| {
+            let mut map = schema.to_map(x);
+            map.remove("genotype");
+            map
+        }).collect()
+    }
+
+    fn compile_filter(filter: &Value, schema: &row::Schema) -> Result<filter::Filter, error::VcfParserError> {
+        filter::Filter::compile(filter, schema, &lookup::Lookups::default())
+    }
+
     #[test]
     fn test_variants() -> Result<(), Box<dyn Error>> {
         let (mut reader, csq_headers, _filter) = prepare_test(None, &vec!["CSQ".to_string(), "Pangolin".to_string()])?;
@@ -258,11 +883,11 @@ mod tests {
             let info_str = str::from_utf8(&info)?;
             info_headers.push(info_str.to_string());
         }
-        let header = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &None);
-        let expected = vec!["chromosome", "position", "id", "reference", "alternative", "qual", "filter", "info.AC", "info.AF", "info.CADD_PHRED", "info.CADD_RAW", "info.CSQ.Allele", "info.CSQ.CANONICAL", "info.CSQ.Consequence", "info.CSQ.Feature", "info.CSQ.Feature_type", "info.CSQ.Gene", "info.CSQ.IMPACT", "info.CSQ.SYMBOL", "info.Pangolin.pangolin_gene", "info.Pangolin.pangolin_max_score", "info.Pangolin.pangolin_transcript", "info.tag", "info.what", "info.who"];
+        let header = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &[], &None)?;
+        let expected = vec!["chromosome", "position", "id", "reference", "alternative", "qual", "filter", "variant_type", "length_change", "is_transition", "end", "sv_type", "mate_chromosome", "mate_position", "cipos", "ciend", "info.AC", "info.AF", "info.CADD_PHRED", "info.CADD_RAW", "info.CSQ.Allele", "info.CSQ.CANONICAL", "info.CSQ.Consequence", "info.CSQ.Feature", "info.CSQ.Feature_type", "info.CSQ.Gene", "info.CSQ.IMPACT", "info.CSQ.SYMBOL", "info.Pangolin.pangolin_gene", "info.Pangolin.pangolin_max_score", "info.Pangolin.pangolin_transcript", "info.tag", "info.what", "info.who"];
         assert_eq!(header, expected);
 
-        let header = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &Some(vec!["info.CSQ.Consequence".to_string(), "reference".to_string()]));
+        let header = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &[], &Some(vec!["info.CSQ.Consequence".to_string(), "reference".to_string()]))?;
         let expected = vec!["info.CSQ.Consequence", "reference"];
         assert_eq!(header, expected);
 
@@ -276,54 +901,109 @@ mod tests {
             let info_str = str::from_utf8(&info)?;
             info_headers.push(info_str.to_string());
         }
-        let header = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &None);
-        let expected = vec!["chromosome", "position", "id", "reference", "alternative", "qual", "filter", "info.AC", "info.AF", "info.CADD_PHRED", "info.CADD_RAW", "info.CSQ.Allele", "info.CSQ.CANONICAL", "info.CSQ.Consequence", "info.CSQ.Feature", "info.CSQ.Feature_type", "info.CSQ.Gene", "info.CSQ.IMPACT", "info.CSQ.SYMBOL", "info.Pangolin.pangolin_gene", "info.Pangolin.pangolin_max_score", "info.Pangolin.pangolin_transcript", "info.tag", "info.what", "info.who", "S1", "S2"];
+        let header = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &[], &None)?;
+        let expected = vec!["chromosome", "position", "id", "reference", "alternative", "qual", "filter", "variant_type", "length_change", "is_transition", "end", "sv_type", "mate_chromosome", "mate_position", "cipos", "ciend", "info.AC", "info.AF", "info.CADD_PHRED", "info.CADD_RAW", "info.CSQ.Allele", "info.CSQ.CANONICAL", "info.CSQ.Consequence", "info.CSQ.Feature", "info.CSQ.Feature_type", "info.CSQ.Gene", "info.CSQ.IMPACT", "info.CSQ.SYMBOL", "info.Pangolin.pangolin_gene", "info.Pangolin.pangolin_max_score", "info.Pangolin.pangolin_transcript", "info.tag", "info.what", "info.who", "S1", "S2"];
         assert_eq!(header, expected);
 
-        let header = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &Some(vec!["info.CSQ.Consequence".to_string(), "reference".to_string()]));
+        let header = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &[], &Some(vec!["info.CSQ.Consequence".to_string(), "reference".to_string()]))?;
         let expected = vec!["info.CSQ.Consequence", "reference"];
         assert_eq!(header, expected);
 
         Ok(())
     }
     #[test]
-    #[should_panic]
-    fn test_get_output_header_panic() {
+    fn test_get_output_header_unknown_column() {
         let (reader, csq_headers, _filter) = prepare_test(None, &vec!["CSQ".to_string(), "Pangolin".to_string()]).unwrap();
         let mut info_headers: Vec<String> = Vec::new();
         for info in reader.header().info_list() {
             let info_str = str::from_utf8(&info).unwrap();
             info_headers.push(info_str.to_string());
         }
-        utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &Some(vec!["info.CSQ.Consequence".to_string(), "doesnotexist".to_string()]));
+        let error = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &[], &Some(vec!["info.CSQ.Consequence".to_string(), "doesnotexist".to_string()]));
+        assert_eq!(error.unwrap_err().to_string(), "Invalid argument: Column doesnotexist is not in the header");
+        let error = utils::get_output_header(&info_headers, &csq_headers, reader.header().samples(), &[], &Some(vec!["info.CSQ.Consequnce".to_string()]));
+        assert_eq!(error.unwrap_err().to_string(), "Invalid argument: Column info.CSQ.Consequnce is not in the header, did you mean info.CSQ.Consequence?");
+    }
+    #[test]
+    fn test_select_columns() -> Result<(), Box<dyn Error>> {
+        let header = ["chromosome", "position", "info.CSQ.Feature", "info.CSQ.IMPACT", "info.gnomAD_nfe_AF", "info.gnomAD_nfe_AC", "info.gnomAD_afr_AF"]
+            .map(String::from);
+        let columns = |patterns: &[&str], exclude: &[&str]| {
+            let patterns = patterns.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+            let exclude = exclude.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+            utils::select_columns(&header, Some(&patterns), &exclude, true)
+        };
+        // the user's order, with globs expanded in header order and not repeating columns
+        assert_eq!(columns(&["position", "info.gnomAD_*_AF", "info.CSQ.*", "chromosome", "info.*"], &[])?,
+            ["position", "info.gnomAD_nfe_AF", "info.gnomAD_afr_AF", "info.CSQ.Feature", "info.CSQ.IMPACT", "chromosome", "info.gnomAD_nfe_AC"]);
+        assert_eq!(columns(&["info.*"], &["info.gnomAD_???_AC", "info.CSQ.IMPACT"])?, ["info.CSQ.Feature", "info.gnomAD_nfe_AF", "info.gnomAD_afr_AF"]);
+        assert_eq!(utils::select_columns(&header, None, &["info.*".to_string()], true)?, ["chromosome", "position"]);
+        assert!(columns(&["info.gnomad_*"], &[]).is_err());
+        assert_eq!(columns(&["IMPACT"], &[]).unwrap_err().to_string(), "Invalid argument: Column IMPACT is not in the header, did you mean info.CSQ.IMPACT?");
+        Ok(())
+    }
+    #[test]
+    fn test_auto_fields() -> Result<(), Box<dyn Error>> {
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test.vcf")?));
+        let vcf_parser = VcfParser::new(Value::Null, vec!["auto".to_string()], None, VcfParserOptions::default(), None, OutputFormat::T, reader)?;
+        assert_eq!(vcf_parser.info_fields, vec!["info.Pangolin", "info.CSQ"]);
+        assert_eq!(vcf_parser.fields_join, vec![vec!["info.Pangolin.pangolin_transcript"], vec!["info.CSQ.Feature"]]);
+        assert_eq!(vcf_parser.csq_headers["Pangolin"], vec!["pangolin_gene", "pangolin_transcript", "pangolin_max_score"]);
+        // plain String fields such as `what` are sampled, and the sampled records are kept for processing
+        assert_eq!(vcf_parser.sampled_lines.len(), 5);
+        // given join keys are used as they are, also Feature
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test.vcf")?));
+        let fields_join = Some(vec!["pangolin_gene".to_string(), "Feature".to_string()]);
+        let vcf_parser = VcfParser::new(Value::Null, vec!["auto".to_string()], fields_join, VcfParserOptions::default(), None, OutputFormat::T, reader)?;
+        assert_eq!(vcf_parser.fields_join, vec![vec!["info.Pangolin.pangolin_gene"], vec!["info.CSQ.Feature"]]);
+        // a join key for each field, also when no nested field is found
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test_sv.vcf")?));
+        let error = VcfParser::new(Value::Null, vec!["auto".to_string()], Some(vec!["Gene".to_string()]), VcfParserOptions::default(), None, OutputFormat::T, reader);
+        assert!(matches!(error.err().and_then(|x| x.downcast::<error::VcfParserError>().ok()), Some(error::VcfParserError::InvalidArgument(_))));
+
+        assert_eq!(utils::parse_nested_format("Consequence annotations from Ensembl VEP. Format: Allele|Consequence|IMPACT"), Some(vec!["Allele".to_string(), "Consequence".to_string(), "IMPACT".to_string()]));
+        assert_eq!(utils::parse_nested_format("Alternate allele count"), None);
+        Ok(())
     }
+
     #[test]
-    fn test_explode_data() -> Result<(), Box<dyn Error>> {
-        let data = serde_json::from_str(r#"{"a":1, "b":2, "c":[{"foo":"A","bar":"B"},{"foo":"a", "bar":"b"}]}"#)?;
-        let exploded = utils::explode_data(data, "c", &vec!["b".to_string(),"c".to_string()]);
+    fn test_explode() -> Result<(), Box<dyn Error>> {
+        let nested = HashMap::from([("c".to_string(), vec!["foo".to_string(), "bar".to_string()])]);
+        let schema = row::Schema::new(&["a".to_string(), "b".to_string()], &nested, &[]);
+        let record: Map<String, Value> = serde_json::from_str(r#"{"a": 1, "info.c.foo": ["A", "a"], "info.c.bar": ["B", "b"]}"#)?;
+        let mut site = typed_row(&schema, &record);
+        site.exploded = false;
+        let exploded = schema.explode(&site, &["info.c".to_string()]);
         let expected: Vec<Map<String, Value>> = vec![
-            serde_json::from_str(r#"{"a":1, "c.foo":"A", "c.bar":"B"}"#).unwrap(),
-            serde_json::from_str(r#"{"a":1, "c.foo":"a", "c.bar":"b"}"#).unwrap(),
+            serde_json::from_str(r#"{"a": 1, "info.c.foo": "A", "info.c.bar": "B", "genotype": {}}"#).unwrap(),
+            serde_json::from_str(r#"{"a": 1, "info.c.foo": "a", "info.c.bar": "b", "genotype": {}}"#).unwrap(),
         ];
-        assert_eq!(exploded, expected);
+        assert_eq!(exploded.len(), 1);
+        assert_eq!(exploded[0].iter().map(|x| schema.to_map(x)).collect::<Vec<Map<String, Value>>>(), expected);
+        // unexploded, the subfields are a list of objects
+        assert_eq!(schema.to_map(&site)["info.c"], serde_json::json!([{"foo": "A", "bar": "B"}, {"foo": "a", "bar": "b"}]));
         Ok(())
     }
 
     #[test]
     fn test_filter() -> Result<(), Box<dyn Error>> {
-        let (mut reader, csq_headers, filter) = prepare_test(None, &vec!["CSQ".to_string(), "Pangolin".to_string()])?;
-        let mut vcf_record = reader.empty_record();
-        let fields = vec!["info.CSQ".to_string(), "info.Pangolin".to_string()];
-        let fields_join = vec!["info.CSQ.Feature".to_string(), "info.Pangolin.pangolin_transcript".to_string()];
-        let mut results: Vec<Map<String,Value>> = Vec::new();
-        while reader.next_record(&mut vcf_record).unwrap() {
-            let variant = Variant::new(&vcf_record, reader.header().samples(), &csq_headers);
-            let explodeds = fields.iter().map(|x| utils::explode_data(serde_json::to_value(&variant).unwrap(), x, &fields)).collect::<Vec<Vec<Map<String, Value>>>>();
-            let joined = utils::outer_join(explodeds, &fields_join)?;
-            let filtered_record: Vec<Map<String, Value>> = joined.into_iter().filter(|x| utils::filter_record(x, &filter)).collect();
-            results.extend(filtered_record.into_iter());
+        let (filter, _) = utils::load_filter_file("test/filter.yml")?;
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test.vcf")?));
+        let fields = vec!["CSQ".to_string(), "Pangolin".to_string()];
+        let fields_join = vec!["Feature".to_string(), "pangolin_transcript".to_string()];
+        let options = VcfParserOptions { join: utils::JoinOptions { join_type: JoinType::Outer, ignore_version: true }, ..Default::default() };
+        let mut vcf_parser = VcfParser::new(filter, fields, Some(fields_join), options, None, OutputFormat::J, reader)?;
+        let schema = &vcf_parser.schema;
+        let mut vcf_record = vcf_parser.reader.empty_record();
+        let mut n_passed = 0;
+        while vcf_parser.reader.next_record(&mut vcf_record)? {
+            let variant = Variant::new(&vcf_record, &vcf_parser.samples, &vcf_parser.csq_headers);
+            let annotations = Map::new();
+            let site = schema.row(&variant, &annotations);
+            let rows = row::join_tables(schema.explode(&site, &vcf_parser.info_fields), &vcf_parser.join_keys, &vcf_parser.join_options);
+            n_passed += rows.iter().filter(|x| vcf_parser.compiled_filter.matches(x, schema)).count();
         }
-        assert_eq!(results.len(), 6);
+        assert_eq!(n_passed, 6);
         Ok(())
     }
 
@@ -337,7 +1017,7 @@ mod tests {
             serde_json::from_str(r#"{"key1": "value1", "key3": "value6"}"#).unwrap(),
             serde_json::from_str(r#"{"key1": "value7", "key3": "value8"}"#).unwrap(),
         ];
-        let joined_table = utils::outer_join(vec![table1, table2], &vec!["key1".to_string(), "key1".to_string()])?;
+        let joined_table = join_rows(&[table1, table2], &[&["key1"], &["key1"]], &utils::JoinOptions { join_type: JoinType::Outer, ignore_version: true });
         let expected: Vec<Map<String, Value>> = vec![
             serde_json::from_str(r#"{"key1": "value1", "key2": "value2", "key3": "value6"}"#).unwrap(),
             serde_json::from_str(r#"{"key1": "value7", "key2": null, "key3": "value8"}"#).unwrap(),
@@ -356,7 +1036,7 @@ mod tests {
             serde_json::from_str(r#"{"key1": "value1", "key3": null}"#).unwrap(),
             serde_json::from_str(r#"{"key1": "value7", "key3": "value8"}"#).unwrap(),
         ];
-        let joined_table = utils::outer_join(vec![table1, table2], &vec!["key1".to_string(), "key1".to_string()])?;
+        let joined_table = join_rows(&[table1, table2], &[&["key1"], &["key1"]], &utils::JoinOptions { join_type: JoinType::Outer, ignore_version: true });
         let expected: Vec<Map<String, Value>> = vec![
             serde_json::from_str(r#"{"key1": "value1", "key2": "value2", "key3": null}"#).unwrap(),
             serde_json::from_str(r#"{"key1": "value7", "key2": null, "key3": "value8"}"#).unwrap(),
@@ -375,7 +1055,7 @@ mod tests {
             serde_json::from_str(r#"{"key11": "value1.8", "key2": "value2"}"#).unwrap(),
             serde_json::from_str(r#"{"key11": "value7", "key2": "value8"}"#).unwrap(),
         ];
-        let joined_table = utils::outer_join(vec![table1, table2], &vec!["key1".to_string(), "key11".to_string()])?;
+        let joined_table = join_rows(&[table1, table2], &[&["key1"], &["key11"]], &utils::JoinOptions { join_type: JoinType::Outer, ignore_version: true });
         let expected: Vec<Map<String, Value>> = vec![
             serde_json::from_str(r#"{"key1": "value1", "key11": "value1.8", "key2": "value2"}"#).unwrap(),
             serde_json::from_str(r#"{"key1": null, "key11": "value7", "key2": "value8"}"#).unwrap(),
@@ -384,6 +1064,680 @@ mod tests {
         assert_eq!(joined_table, expected);
         Ok(())
     }
+    #[test]
+    fn test_join_types() -> Result<(), Box<dyn Error>> {
+        let table1: Vec<Map<String, Value>> = vec![
+            serde_json::from_str(r#"{"t1.gene": "g1", "t1.tx": "tx1.2", "t1.score": 1}"#).unwrap(),
+            serde_json::from_str(r#"{"t1.gene": "g1", "t1.tx": "tx2", "t1.score": 2}"#).unwrap(),
+        ];
+        let table2: Vec<Map<String, Value>> = vec![
+            serde_json::from_str(r#"{"t2.gene": "g1", "t2.tx": "tx1", "t2.score": 3}"#).unwrap(),
+            serde_json::from_str(r#"{"t2.gene": "g2", "t2.tx": "tx2", "t2.score": 4}"#).unwrap(),
+        ];
+        let keys: [&[&str]; 2] = [&["t1.gene", "t1.tx"], &["t2.gene", "t2.tx"]];
+        let join = |join_type, ignore_version| join_rows(&[table1.clone(), table2.clone()], &keys, &utils::JoinOptions { join_type, ignore_version });
 
-    
-}
\ No newline at end of file
+        // composite key: only g1/tx1 matches, and only if the version is ignored
+        let joined = join(JoinType::Inner, true);
+        let expected: Vec<Map<String, Value>> = vec![
+            serde_json::from_str(r#"{"t1.gene": "g1", "t1.tx": "tx1.2", "t1.score": 1, "t2.gene": "g1", "t2.tx": "tx1", "t2.score": 3}"#).unwrap(),
+        ];
+        assert_eq!(joined, expected);
+        assert!(join(JoinType::Inner, false).is_empty());
+
+        // left join keeps the unmatched row of the first table only
+        let joined = join(JoinType::Left, true);
+        assert_eq!(joined.len(), 2);
+        assert_eq!(joined[1]["t1.tx"], "tx2");
+        assert_eq!(joined[1]["t2.score"], Value::Null);
+
+        assert_eq!(join(JoinType::Outer, true).len(), 3);
+        assert_eq!(join(JoinType::Outer, false).len(), 4);
+
+        // rows without a key never match, not even each other
+        let table1: Vec<Map<String, Value>> = vec![serde_json::from_str(r#"{"t1.tx": null, "t1.score": 1}"#)?];
+        let table2: Vec<Map<String, Value>> = vec![serde_json::from_str(r#"{"t2.tx": null, "t2.score": 3}"#)?];
+        let join = |join_type| join_rows(&[table1.clone(), table2.clone()], &[&["t1.tx"], &["t2.tx"]], &utils::JoinOptions { join_type, ignore_version: false });
+        assert!(join(JoinType::Inner).is_empty());
+        assert_eq!(join(JoinType::Left), vec![serde_json::from_str::<Map<String, Value>>(r#"{"t1.tx": null, "t1.score": 1, "t2.tx": null, "t2.score": null}"#)?]);
+        assert_eq!(join(JoinType::Outer).len(), 2);
+
+        // duplicate keys join every left row with every right row of the key, whatever their order
+        let table1: Vec<Map<String, Value>> = vec![
+            serde_json::from_str(r#"{"t1.tx": "tx1", "t1.score": 1}"#)?,
+            serde_json::from_str(r#"{"t1.tx": "tx1", "t1.score": 2}"#)?,
+        ];
+        let table2: Vec<Map<String, Value>> = vec![
+            serde_json::from_str(r#"{"t2.tx": "tx1", "t2.score": 3}"#)?,
+            serde_json::from_str(r#"{"t2.tx": "tx1", "t2.score": 4}"#)?,
+        ];
+        let scores = |tables: [Vec<Map<String, Value>>; 2]| {
+            let mut scores = join_rows(&tables, &[&["t1.tx"], &["t2.tx"]], &utils::JoinOptions { join_type: JoinType::Outer, ignore_version: false })
+                .iter().map(|x| (x["t1.score"].as_i64().unwrap(), x["t2.score"].as_i64().unwrap())).collect::<Vec<(i64, i64)>>();
+            scores.sort();
+            scores
+        };
+        assert_eq!(scores([table1.clone(), table2.clone()]), vec![(1, 3), (1, 4), (2, 3), (2, 4)]);
+        assert_eq!(scores([table1.into_iter().rev().collect(), table2]), vec![(1, 3), (1, 4), (2, 3), (2, 4)]);
+
+        // chr1 220796686 has neither a CSQ Feature nor a Pangolin transcript
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test.vcf")?));
+        let fields = vec!["CSQ".to_string(), "Pangolin".to_string()];
+        let fields_join = vec!["Feature".to_string(), "pangolin_transcript".to_string()];
+        let options = VcfParserOptions { join: utils::JoinOptions { join_type: JoinType::Inner, ignore_version: false }, ..Default::default() };
+        let mut vcf_parser = VcfParser::new(Value::Null, fields, Some(fields_join), options, None, OutputFormat::J, reader)?;
+        let schema = &vcf_parser.schema;
+        let mut vcf_record = vcf_parser.reader.empty_record();
+        let mut positions = Vec::new();
+        while vcf_parser.reader.next_record(&mut vcf_record)? {
+            let variant = Variant::new(&vcf_record, &vcf_parser.samples, &vcf_parser.csq_headers);
+            let annotations = Map::new();
+            let site = schema.row(&variant, &annotations);
+            let rows = row::join_tables(schema.explode(&site, &vcf_parser.info_fields), &vcf_parser.join_keys, &vcf_parser.join_options);
+            positions.extend(rows.iter().map(|_| variant.position));
+        }
+        assert!(!positions.is_empty());
+        assert!(!positions.contains(&220796686));
+        Ok(())
+    }
+    #[test]
+    fn test_filter_unexploded() -> Result<(), Box<dyn Error>> {
+        let nested = HashMap::from([("CSQ".to_string(), vec!["IMPACT".to_string()])]);
+        let schema = row::Schema::new(&["info.AF".to_string()], &nested, &[]);
+        let record: Map<String, Value> = serde_json::from_str(r#"{"info.AF": 0.001, "info.CSQ.IMPACT": ["HIGH", "MODIFIER"]}"#)?;
+        let mut site = typed_row(&schema, &record);
+        site.exploded = false;
+        let mut filter = parser::parse_logic_expr("info.AF < 0.01 AND info.CSQ.IMPACT in (HIGH, MODERATE)")?;
+        assert!(compile_filter(&filter, &schema)?.matches(&site, &schema));
+        utils::set_array_match(&mut filter, "all");
+        assert!(!compile_filter(&filter, &schema)?.matches(&site, &schema));
+        // exploded rows have no arrays for `all` to apply to
+        let error = run(Args::parse_from(["vcf_parser", "-i", "test/test.vcf", "--array-match", "all", "-f", "info.CSQ.IMPACT == HIGH"]));
+        assert_eq!(error.unwrap_err().to_string(), "Invalid argument: --array-match all needs --no-explode");
+        Ok(())
+    }
+    #[test]
+    fn test_derived_columns() -> Result<(), Box<dyn Error>> {
+        let record: Map<String, Value> = serde_json::from_str(r#"{"info.AC": 3, "info.AF_afr": 0.01, "info.AF_nfe": null, "score": -0.8, "symbol": "CALCRL"}"#)?;
+        let derived = vec![
+            derive::DerivedColumn::parse("ac_plus=info.AC + 1")?,
+            derive::DerivedColumn::parse("popmax=max(info.AF_afr, info.AF_nfe)")?,
+            derive::DerivedColumn::parse("abs_score = max(score, -score)")?,
+            derive::DerivedColumn::parse(r#"label=if(abs_score >= 0.5, concat(lower(symbol), "_splice"), none)"#)?,
+            derive::DerivedColumn::parse("missing=info.AF_nfe * 2")?,
+        ];
+        // derived columns are in the schema, after the columns of the record
+        let derived_row = |record: &Map<String, Value>, derived: &[derive::DerivedColumn]| {
+            let mut columns = record.keys().cloned().collect::<Vec<String>>();
+            columns.extend(derived.iter().map(|x| x.name.to_string()));
+            let schema = row::Schema::new(&columns, &HashMap::new(), &[]);
+            let mut row = typed_row(&schema, record);
+            derive::add_row_columns(&mut row, &schema, derived);
+            (schema, row)
+        };
+        let (schema, row) = derived_row(&record, &derived);
+        let record = schema.to_map(&row);
+        assert_eq!(record["ac_plus"], 4);
+        assert_eq!(record["popmax"], 0.01);
+        assert_eq!(record["abs_score"], 0.8);
+        assert_eq!(record["label"], "calcrl_splice");
+        assert_eq!(record["missing"], Value::Null);
+        assert!(derive::DerivedColumn::parse("x=nosuchfunction(a)").is_err());
+        // functions are checked for their number of arguments, rather than failing on the first record
+        assert!(derive::DerivedColumn::parse("x=if()").is_err());
+        assert!(derive::DerivedColumn::parse("x=substr(symbol)").is_err());
+        assert!(derive::DerivedColumn::parse("x=abs(score, 1)").is_err());
+        assert!(derive::DerivedColumn::parse("x=max(score, 1, 2)").is_ok());
+
+        // derive section of a filter yaml, in order
+        let (filter, derived) = utils::load_filter_file("test/derive.yml")?;
+        assert_eq!(derived.iter().map(|x| x.name.as_str()).collect::<Vec<&str>>(), vec!["pangolin_abs", "splicing"]);
+        let record: Map<String, Value> = serde_json::from_str(r#"{"info.CADD_PHRED": 0.4, "info.Pangolin.pangolin_max_score": -0.56}"#)?;
+        let (schema, row) = derived_row(&record, &derived);
+        assert!(compile_filter(&filter, &schema)?.matches(&row, &schema));
+        Ok(())
+    }
+    #[test]
+    fn test_variant_class() -> Result<(), Box<dyn Error>> {
+        assert_eq!(variant::classify("A", "G"), "SNV");
+        assert_eq!(variant::classify("AC", "GT"), "MNV");
+        assert_eq!(variant::classify("A", "AT"), "insertion");
+        assert_eq!(variant::classify("AT", "A"), "deletion");
+        assert_eq!(variant::classify("AT", "GCC"), "complex");
+        assert_eq!(variant::classify("N", "<DEL>"), "symbolic");
+        assert_eq!(variant::classify("G", "G]17:198982]"), "breakend");
+        assert_eq!(variant::classify("A", "*"), "spanning_deletion");
+        assert_eq!(variant::classify("A", "."), "no_alt");
+
+        let (mut reader, csq_headers, _filter) = prepare_test(None, &vec!["CSQ".to_string()])?;
+        let mut vcf_record = reader.empty_record();
+        let mut variants: Vec<Variant> = Vec::new();
+        while reader.next_record(&mut vcf_record).unwrap() {
+            variants.push(Variant::new(&vcf_record, reader.header().samples(), &csq_headers));
+        }
+        // chr1 A>G, chr2 A>AT
+        assert_eq!((variants[0].variant_type.as_str(), variants[0].is_transition, variants[0].length_change), ("SNV", Some(true), Some(0)));
+        assert_eq!((variants[2].variant_type.as_str(), variants[2].is_transition, variants[2].length_change), ("insertion", None, Some(1)));
+        assert_eq!(variants[2].end, 187385546);
+        Ok(())
+    }
+    #[test]
+    fn test_structural_variants() -> Result<(), Box<dyn Error>> {
+        assert_eq!(variant::sv_type_from_alt("<DUP:TANDEM>"), Some("DUP".to_string()));
+        assert_eq!(variant::sv_type_from_alt("N[chr2:123["), Some("BND".to_string()));
+        assert_eq!(variant::sv_type_from_alt("A"), None);
+        assert_eq!(variant::parse_mate("]chr2:123]N"), Some(("chr2".to_string(), 123)));
+        assert_eq!(variant::parse_mate("N[<ctg1>:5["), Some(("ctg1".to_string(), 5)));
+
+        let region = utils::Region::parse("chr1:1000-2000")?;
+        let deletion: Map<String, Value> = serde_json::from_str(r#"{"chromosome": "chr1", "position": 500, "end": 1500}"#)?;
+        let breakend: Map<String, Value> = serde_json::from_str(r#"{"chromosome": "chr2", "position": 500, "end": 500, "mate_chromosome": "chr1", "mate_position": 1200}"#)?;
+        let snv: Map<String, Value> = serde_json::from_str(r#"{"chromosome": "chr1", "position": 999, "end": 999}"#)?;
+        let (schema, tables) = typed_rows(&[vec![deletion, breakend, snv]]);
+        let [deletion, breakend, snv] = &tables[0][..] else { unreachable!() };
+        assert!(schema.overlaps(deletion, &region));
+        assert!(schema.overlaps(breakend, &region));
+        assert!(!schema.overlaps(snv, &region));
+        let filter = compile_filter(&parser::parse_logic_expr(r#"variant overlaps "chr1:1000-2000""#)?, &schema)?;
+        assert!(filter.matches(deletion, &schema));
+        assert!(!filter.matches(snv, &schema));
+        // malformed regions are reported when the filter is loaded
+        let filter = parser::parse_logic_expr(r#"variant overlaps "chr1:a-b""#)?;
+        assert!(filter_library::FilterLibrary::default().resolve(&filter).is_err());
+        assert!(compile_filter(&filter, &schema).is_err());
+
+        let (mut reader, csq_headers, _filter) = prepare_test(Some("test/test_sv.vcf"), &vec![])?;
+        let mut vcf_record = reader.empty_record();
+        let mut variants: Vec<Variant> = Vec::new();
+        while reader.next_record(&mut vcf_record).unwrap() {
+            variants.push(Variant::new(&vcf_record, reader.header().samples(), &csq_headers));
+        }
+        assert_eq!((variants[0].sv_type.as_deref(), variants[0].end, variants[0].length_change), (Some("DEL"), 5000, Some(-4000)));
+        assert_eq!((variants[0].cipos.clone(), variants[0].ciend.clone()), (Some(vec![-10, 10]), Some(vec![-20, 20])));
+        // no END, so the interval comes from SVLEN
+        assert_eq!((variants[1].sv_type.as_deref(), variants[1].end), (Some("DUP"), 9500));
+        assert_eq!((variants[2].mate_chromosome.as_deref(), variants[2].mate_position), (Some("chr2"), Some(123456)));
+        assert_eq!(variants[3].sv_type, None);
+
+        // a negative END is ignored rather than wrapping around
+        let path = std::env::temp_dir().join("vcf_parser_test_end.vcf");
+        let sv = std::fs::read_to_string("test/test_sv.vcf")?;
+        let header = sv.lines().filter(|x| x.starts_with('#')).collect::<Vec<&str>>().join("\n");
+        std::fs::write(&path, format!("{}\nchr1\t1000\tdel2\tNAC\t*\t.\tPASS\tEND=-5\tGT\t0/1\n", header))?;
+        let (mut reader, csq_headers, _filter) = prepare_test(path.to_str(), &vec![])?;
+        let mut vcf_record = reader.empty_record();
+        reader.next_record(&mut vcf_record)?;
+        let variant = Variant::new(&vcf_record, reader.header().samples(), &csq_headers);
+        assert_eq!((variant.variant_type.as_str(), variant.end, variant.length_change), ("spanning_deletion", 1002, None));
+        Ok(())
+    }
+    #[test]
+    fn test_inheritance() -> Result<(), Box<dyn Error>> {
+        assert_eq!(utils::alt_allele_count("0|1"), Some((1, 2)));
+        assert_eq!(utils::alt_allele_count("1"), Some((1, 1)));
+        assert_eq!(utils::alt_allele_count("./1"), None);
+
+        let pedigree = pedigree::Pedigree::from_file("test/test_trio.ped", 20, 0)?;
+        let (mut reader, csq_headers, _filter) = prepare_test(Some("test/test_trio.vcf"), &vec![])?;
+        let mut vcf_record = reader.empty_record();
+        let mut variants: Vec<Variant> = Vec::new();
+        while reader.next_record(&mut vcf_record).unwrap() {
+            variants.push(Variant::new(&vcf_record, reader.header().samples(), &csq_headers));
+        }
+        let annotations = variants.iter().map(|x| {
+            Map::from_iter([("inheritance".to_string(), pedigree.inheritance(&x.chromosome, &x.genotype).into())])
+        }).collect::<Vec<Map<String, Value>>>();
+        let schema = row::Schema::new(&["chromosome".to_string(), "inheritance".to_string()], &csq_headers, &[]);
+        let sites = variants.iter().zip(&annotations).map(|(x, y)| schema.row(x, y)).collect::<Vec<row::Row>>();
+        let models = sites.iter().map(|x| schema.get(x, "inheritance").unwrap().to_value()).collect::<Vec<Value>>();
+        // the father of the third variant has GQ 5, so it can't be called de novo
+        assert_eq!(models, vec![
+            serde_json::json!(["de_novo", "ad"]),
+            serde_json::json!(["ar_hom"]),
+            serde_json::json!(["ad"]),
+            serde_json::json!(["x_linked"]),
+        ]);
+        let filter = compile_filter(&parser::parse_logic_expr("inheritance in (de_novo, ar_hom)")?, &schema)?;
+        assert_eq!(sites.iter().filter(|x| filter.matches(x, &schema)).count(), 2);
+
+        // parents are looked up in the child's family, so a DAD of another family is not KID2's father
+        let path = std::env::temp_dir().join("vcf_parser_test_families.ped");
+        std::fs::write(&path, "FAM1\tDAD\t0\t0\t1\t1\nFAM2\tMOM2\t0\t0\t2\t1\nFAM2\tKID2\tDAD\tMOM2\t1\t2\n")?;
+        let pedigree = pedigree::Pedigree::from_file(path.to_str().unwrap(), 20, 0)?;
+        let genotype: Map<String, Value> = serde_json::from_str(r#"{"DAD": {"GT": "0/0"}, "MOM2": {"GT": "0/0"}, "KID2": {"GT": "0/1"}}"#)?;
+        assert_eq!(pedigree.inheritance("chr1", &genotype), vec!["ad"]);
+
+        // the selected samples don't change the models, as the parents are read from the record
+        let path = std::env::temp_dir().join("vcf_parser_test_inheritance.tsv");
+        let path = path.to_str().unwrap();
+        let models = |samples: &[&str]| -> Result<Vec<String>, Box<dyn Error>> {
+            let args = ["vcf_parser", "-i", "test/test_trio.vcf", "--fields", "auto", "--ped", "test/test_trio.ped", "-c", "position,inheritance", "-o", path];
+            run(Args::parse_from(args.iter().chain(samples)))?;
+            Ok(std::fs::read_to_string(path)?.lines().skip(1).map(String::from).collect())
+        };
+        let all = models(&[])?;
+        assert_eq!(all[..2], ["100\t[\"de_novo\",\"ad\"]", "200\t[\"ar_hom\"]"]);
+        assert_eq!(models(&["--samples", "KID"])?, all);
+        assert_eq!(models(&["--exclude-samples", "DAD"])?, all);
+        Ok(())
+    }
+    #[test]
+    fn test_comphet() -> Result<(), Box<dyn Error>> {
+        let pedigree = pedigree::Pedigree::from_file("test/test_trio.ped", 20, 0)?;
+        let row = |position: u64, gene: &str| -> Map<String, Value> {
+            serde_json::from_value(serde_json::json!({
+                "chromosome": "chr1", "position": position, "reference": "A", "alternative": "G", "info.CSQ.Gene": gene, "comphet_id": null,
+                "genotype": {"KID": {"GT": "0/1"}},
+            })).unwrap()
+        };
+        let (schema, tables) = typed_rows(&[vec![row(300, "GENE1"), row(100, "GENE1"), row(200, "GENE1"), row(400, "GENE2")]]);
+        // only KID is output, as with --samples KID, but the parents are in the genotypes of the record.
+        // Two paternal variants can't pair, and GENE2 has only one variant
+        let parents = [("0/1", "0/0"), ("0/1", "0/0"), ("0/0", "0/1"), ("0/0", "0/1")];
+        let rows = tables[0].iter().zip(parents).map(|(row, (dad, mom))| {
+            let genotype = serde_json::from_value(serde_json::json!({"DAD": {"GT": dad}, "MOM": {"GT": mom}, "KID": {"GT": "0/1"}})).unwrap();
+            comphet::Candidate { row: row.clone(), input: 0, hets: comphet::het_calls(&pedigree, &genotype) }
+        }).collect::<Vec<comphet::Candidate>>();
+        let pairs = comphet::annotate(rows, &[&schema], comphet::DEFAULT_GENE_COLUMN);
+        let ids = pairs.iter().map(|x| {
+            let row = schema.to_map(&x.row);
+            (row["position"].as_u64().unwrap(), row["comphet_id"].clone())
+        }).collect::<Vec<(u64, Value)>>();
+        assert_eq!(ids, vec![
+            (100, serde_json::json!(["KID:GENE1:1"])),
+            (200, serde_json::json!(["KID:GENE1:1", "KID:GENE1:2"])),
+            (300, serde_json::json!(["KID:GENE1:2"])),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate() -> Result<(), Box<dyn Error>> {
+        let args = aggregate::AggregateArgs {
+            by: "info.CSQ.SYMBOL".to_string(),
+            consequence: "info.CSQ.Consequence".to_string(),
+            max: vec!["info.CADD_PHRED".to_string()],
+            min: vec![],
+            per_sample: true,
+        };
+        let row = |position: u64, consequence: &str, cadd: f64, gts: [&str; 2]| -> Map<String, Value> {
+            serde_json::from_value(serde_json::json!({
+                "chromosome": "chr2", "position": position, "reference": "A", "alternative": "T",
+                "info.CSQ.SYMBOL": "CALCRL", "info.CSQ.Consequence": consequence, "info.CADD_PHRED": cadd,
+                "genotype": {"S1": {"GT": gts[0]}, "S2": {"GT": gts[1]}},
+            })).unwrap()
+        };
+        // partial aggregates from two threads, with two transcripts of the same variant
+        let (schema, tables) = typed_rows(&[vec![
+            row(100, "missense_variant", 20.0, ["0/1", "0/0"]),
+            row(100, "intron_variant", 20.0, ["0/1", "0/0"]),
+            row(200, "frameshift_variant&splice_region_variant", 34.0, ["0/1", "./."]),
+        ]]);
+        let mut first = aggregate::Aggregator::new(args.clone(), vec!["S1".to_string(), "S2".to_string()]);
+        first.add(&tables[0][0], &schema);
+        first.add(&tables[0][1], &schema);
+        let mut second = aggregate::Aggregator::new(args, vec!["S1".to_string(), "S2".to_string()]);
+        second.add(&tables[0][2], &schema);
+        let rows = first.merge(second).rows();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["n_variants"], 2);
+        assert_eq!(rows[0]["n_carriers"], 1);
+        assert_eq!(rows[0]["top_consequence"], "frameshift_variant");
+        assert_eq!(rows[0]["max.info.CADD_PHRED"], 34.0);
+        assert_eq!((rows[0]["S1"].clone(), rows[0]["S2"].clone()), (Value::from(2), Value::from(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cohort_stats() -> Result<(), Box<dyn Error>> {
+        let cohort = cohort::CohortStats::from_file("test/test_trio_groups.txt")?;
+        assert_eq!(cohort.columns().len(), 18);
+        let genotype: Map<String, Value> = serde_json::from_str(r#"{"DAD": {"GT": "0/1"}, "MOM": {"GT": "./."}, "KID": {"GT": "1|1"}}"#)?;
+        let mut site = Map::new();
+        cohort.annotate(&mut site, &genotype);
+        assert_eq!((site["cohort.AC"].clone(), site["cohort.AN"].clone(), site["cohort.AF"].clone()), (Value::from(3), Value::from(4), Value::from(0.75)));
+        assert_eq!((site["cohort.n_het"].clone(), site["cohort.n_hom_alt"].clone()), (Value::from(1), Value::from(1)));
+        assert_eq!(site["cohort.call_rate"], Value::from(2.0 / 3.0));
+        assert_eq!((site["cohort.parents.AC"].clone(), site["cohort.parents.call_rate"].clone()), (Value::from(1), Value::from(0.5)));
+        // integer columns equal the float numbers of filters
+        let (schema, tables) = typed_rows(&[vec![site]]);
+        let filter = compile_filter(&parser::parse_logic_expr("cohort.kids.n_hom_alt = 1 and cohort.AF < 0.8")?, &schema)?;
+        assert!(filter.matches(&tables[0][0], &schema));
+
+        // the selected samples don't change the statistics
+        let path = std::env::temp_dir().join("vcf_parser_test_cohort.tsv");
+        let path = path.to_str().unwrap();
+        run(Args::parse_from(["vcf_parser", "-i", "test/test_trio.vcf", "--fields", "auto", "--samples", "KID", "--cohort-groups", "test/test_trio_groups.txt",
+            "-c", "position,cohort.AN,cohort.parents.AC", "-o", path]))?;
+        let output = std::fs::read_to_string(path)?;
+        let rows = output.lines().skip(1).map(|x| x.split('\t').skip(1).collect::<Vec<&str>>().join(" ")).collect::<Vec<String>>();
+        assert_eq!(rows, ["6 0", "6 2", "6 0", "4 1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<(), Box<dyn Error>> {
+        let args = stats::StatsArgs { histogram: vec!["info.CADD_PHRED:10".to_string()] };
+        assert!(stats::Stats::new(&stats::StatsArgs { histogram: vec!["info.CADD_PHRED:0".to_string()] }, &[]).is_err());
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test_samples.vcf")?));
+        let mut vcf_parser = VcfParser::new(Value::Null, vec!["CSQ".to_string()], Some(vec!["Feature".to_string()]), VcfParserOptions::default(), None, OutputFormat::J, reader)?;
+        let schema = &vcf_parser.schema;
+        let mut vcf_record = vcf_parser.reader.empty_record();
+        // one partial summary per record, as if each was parsed on its own thread
+        let mut partials: Vec<stats::Stats> = Vec::new();
+        while vcf_parser.reader.next_record(&mut vcf_record)? {
+            let variant = Variant::new(&vcf_record, &vcf_parser.samples, &vcf_parser.csq_headers);
+            let annotations = Map::new();
+            let site = schema.row(&variant, &annotations);
+            let rows = row::join_tables(schema.explode(&site, &vcf_parser.info_fields), &vcf_parser.join_keys, &vcf_parser.join_options);
+            let mut partial = stats::Stats::new(&args, &vcf_parser.info_fields)?;
+            partial.add(&rows.iter().collect::<Vec<&row::Row>>(), schema);
+            partials.push(partial);
+        }
+        let report = partials.into_iter().reduce(|a, b| a.merge(b)).unwrap().to_json();
+        assert_eq!(report["records"], 5);
+        assert_eq!(report["chromosome"], serde_json::json!({"chr1": 1, "chr2": 4}));
+        assert_eq!(report["variant_type"], serde_json::json!({"SNV": 4, "insertion": 1}));
+        assert_eq!(report["ti_tv"]["ratio"], 1.0);
+        // records, not transcripts, with a HIGH impact
+        assert_eq!(report["impact"]["HIGH"], 4);
+        assert_eq!(report["samples"]["S1"]["missingness"], 0.2);
+        assert_eq!(report["histograms"]["info.CADD_PHRED"]["20"], 2);
+
+        // impacts of the nested fields parsed, such as ANN rather than CSQ
+        let rows: Vec<Map<String, Value>> = vec![
+            serde_json::from_str(r#"{"chromosome": "chr1", "info.ANN.IMPACT": "HIGH", "info.CSQ.IMPACT": "LOW"}"#)?,
+            serde_json::from_str(r#"{"chromosome": "chr1", "info.ANN.IMPACT": "HIGH"}"#)?,
+        ];
+        let (schema, tables) = typed_rows(&[rows]);
+        let mut partial = stats::Stats::new(&args, &["info.ANN".to_string()])?;
+        partial.add(&tables[0].iter().collect::<Vec<&row::Row>>(), &schema);
+        assert_eq!(partial.to_json()["impact"], serde_json::json!({"HIGH": 1}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_selection() -> Result<(), Box<dyn Error>> {
+        let samples = SampleOptions {
+            include: Some(vec!["KID".to_string(), "DAD".to_string(), "MOM".to_string()]),
+            exclude: vec!["MOM".to_string()],
+            rename: utils::load_sample_names("test/test_trio_rename.tsv")?,
+        };
+        let options = VcfParserOptions { samples, ..Default::default() };
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test_trio.vcf")?));
+        let mut vcf_parser = VcfParser::new(Value::Null, vec!["auto".to_string()], None, options, None, OutputFormat::T, reader)?;
+        assert_eq!(vcf_parser.tsv_headers[vcf_parser.tsv_headers.len() - 2..], ["PROBAND".to_string(), "DAD".to_string()]);
+        let mut vcf_record = vcf_parser.reader.empty_record();
+        vcf_parser.reader.next_record(&mut vcf_record)?;
+        let mut variant = Variant::with_projection(&vcf_record, &vcf_parser.samples, &vcf_parser.csq_headers, vcf_parser.projection.as_ref());
+        variant.rename_samples(&vcf_parser.sample_names);
+        assert_eq!(variant.genotype.keys().collect::<Vec<&String>>(), ["DAD", "PROBAND"]);
+
+        let samples = SampleOptions { include: Some(vec!["NOPE".to_string()]), ..Default::default() };
+        let options = VcfParserOptions { samples, ..Default::default() };
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test_trio.vcf")?));
+        assert!(VcfParser::new(Value::Null, vec!["auto".to_string()], None, options, None, OutputFormat::T, reader).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_typed_rows() -> Result<(), Box<dyn Error>> {
+        // a site row is the variant as json, and its exploded rows are joined on the transcript
+        let (filter, _) = utils::load_filter_file("test/filter.yml")?;
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test.vcf")?));
+        let fields = vec!["CSQ".to_string(), "Pangolin".to_string()];
+        let fields_join = vec!["Feature".to_string(), "pangolin_transcript".to_string()];
+        let mut vcf_parser = VcfParser::new(filter, fields, Some(fields_join), VcfParserOptions::default(), None, OutputFormat::J, reader)?;
+        let schema = &vcf_parser.schema;
+        let mut vcf_record = vcf_parser.reader.empty_record();
+        let mut n_passed = 0;
+        while vcf_parser.reader.next_record(&mut vcf_record)? {
+            let variant = Variant::new(&vcf_record, &vcf_parser.samples, &vcf_parser.csq_headers);
+            let annotations = Map::new();
+            let site = schema.row(&variant, &annotations);
+            assert_eq!(Value::Object(schema.to_map(&site)), serde_json::to_value(&variant)?);
+            let rows = row::join_tables(schema.explode(&site, &vcf_parser.info_fields), &vcf_parser.join_keys, &vcf_parser.join_options);
+            for row in &rows {
+                let transcripts = ["info.CSQ.Feature", "info.Pangolin.pangolin_transcript"].map(|x| schema.get(row, x).and_then(|x| x.as_str()));
+                if let [Some(x), Some(y)] = transcripts {
+                    assert_eq!(x, y);
+                }
+                n_passed += vcf_parser.compiled_filter.matches(row, schema) as usize;
+            }
+        }
+        assert_eq!(n_passed, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bgzf() -> Result<(), Box<dyn Error>> {
+        // test.vcf.bgz has 1kb blocks, so blocks are inflated in several batches
+        let mut file = BufReader::new(File::open("test/test.vcf.bgz")?);
+        assert!(bgzf::is_bgzf(file.fill_buf()?));
+        let mut data = String::new();
+        io::Read::read_to_string(&mut bgzf::BgzfReader::new(file, 1)?, &mut data)?;
+        assert_eq!(data, std::fs::read_to_string("test/test.vcf")?);
+        assert!(!bgzf::is_bgzf(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 0, 0]));
+        // --threads is shared between inflating and parsing
+        assert!(is_bgzf_input("test/test.vcf.bgz") && !is_bgzf_input("test/test.vcf"));
+        assert_eq!(split_threads(8, true), (6, 2));
+        assert_eq!(split_threads(2, true), (1, 1));
+        assert_eq!(split_threads(8, false), (8, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_indexed_output() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join("vcf_parser_test_indexed.tsv.gz");
+        let path = path.to_str().unwrap();
+        run(Args::parse_from(["vcf_parser", "-i", "test/test.vcf", "-c", "chromosome,position,info.CSQ.Feature", "-o", path, "--index"]))?;
+        let mut data = String::new();
+        io::Read::read_to_string(&mut bgzf::BgzfReader::new(File::open(path)?, 1)?, &mut data)?;
+        let lines = data.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], "chromosome\tposition\tinfo.CSQ.Feature");
+        // rows keep the order of the VCF
+        assert!(lines[1].starts_with("chr1\t220796686\t") && lines.last().unwrap().starts_with("chr2\t187385587\t"));
+        let mut index = Vec::new();
+        io::Read::read_to_end(&mut bgzf::BgzfReader::new(File::open(format!("{}.tbi", path))?, 1)?, &mut index)?;
+        // 2 chromosomes, generic format, columns 1, 2 and no end column, # comments, 1 header line, then the names
+        let header = [2, 0, 1, 2, 0, 35, 1, 10].iter().flat_map(|x: &i32| x.to_le_bytes()).collect::<Vec<u8>>();
+        assert_eq!(&index[..4], b"TBI\x01");
+        assert_eq!(&index[4..36], header.as_slice());
+        assert_eq!(&index[36..46], b"chr1\0chr2\0");
+
+        // an invalid --index is reported before the output file is created
+        for (path, format, columns) in [("vcf_parser_test_indexed.json", "j", "chromosome,position"), ("vcf_parser_test_indexed.tsv", "t", "chromosome,position"),
+            ("vcf_parser_test_indexed_columns.tsv.gz", "t", "position")] {
+            let path = std::env::temp_dir().join(path);
+            let _ = std::fs::remove_file(&path);
+            assert!(run(Args::parse_from(["vcf_parser", "-i", "test/test.vcf", "-c", columns, "-o", path.to_str().unwrap(), "--output-format", format, "--index"])).is_err());
+            assert!(!path.exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_output() -> Result<(), Box<dyn Error>> {
+        // rows of every worker end up whole in the output, whichever order the batches come in
+        let mut outputs = Vec::new();
+        for threads in ["1", "4"] {
+            let path = std::env::temp_dir().join(format!("vcf_parser_test_batched_{}.tsv", threads));
+            let path = path.to_str().unwrap();
+            run(Args::parse_from(["vcf_parser", "-i", "test/test.vcf", "-t", threads, "-o", path]))?;
+            let data = std::fs::read_to_string(path)?;
+            let mut lines = data.lines().map(String::from).collect::<Vec<String>>();
+            lines[1..].sort();
+            outputs.push(lines);
+        }
+        assert!(outputs[0].len() > 10);
+        assert_eq!(outputs[0], outputs[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_inputs() -> Result<(), Box<dyn Error>> {
+        // split test.vcf by chromosome, with CADD_RAW only in the chr1 file
+        let vcf = std::fs::read_to_string("test/test.vcf")?;
+        let header = vcf.lines().filter(|x| x.starts_with('#')).collect::<Vec<&str>>();
+        let mut paths = Vec::new();
+        for chromosome in ["chr1", "chr2"] {
+            let path = std::env::temp_dir().join(format!("vcf_parser_test_{}.vcf", chromosome)).to_str().unwrap().to_string();
+            let lines = header.iter().filter(|x| chromosome == "chr1" || !x.contains("ID=CADD_RAW")).map(|x| x.to_string())
+                .chain(vcf.lines().filter(|x| x.starts_with(&format!("{}\t", chromosome))).map(|x| if chromosome == "chr1" { x.to_string() } else { x.replace("CADD_RAW=", "CADD=") }))
+                .collect::<Vec<String>>();
+            std::fs::write(&path, lines.join("\n") + "\n")?;
+            paths.push(path);
+        }
+        let path = std::env::temp_dir().join("vcf_parser_test_inputs.tsv");
+        let path = path.to_str().unwrap();
+        run(Args::parse_from(["vcf_parser", "-i", &paths[0], "-i", &paths[1], "-o", path, "-t", "1"]))?;
+        let data = std::fs::read_to_string(path)?;
+        let lines = data.lines().map(|x| x.split('\t').collect::<Vec<&str>>()).collect::<Vec<Vec<&str>>>();
+        let column = |name: &str| lines[0].iter().position(|x| *x == name).unwrap();
+        let (cadd_raw, source_file) = (column("info.CADD_RAW"), column("source_file"));
+        assert_eq!(lines.len(), 15);
+        for line in &lines[1..] {
+            assert_eq!(line.len(), lines[0].len());
+            let (chr1, chr2) = (line[source_file] == paths[0], line[source_file] == paths[1]);
+            assert!(if line[0] == "chr1" { chr1 && !line[cadd_raw].is_empty() } else { chr2 && line[cadd_raw].is_empty() });
+        }
+        // selected columns only have to be in one input
+        run(Args::parse_from(["vcf_parser", "-i", &paths[0], "-i", &paths[1], "-o", path, "-c", "position,info.CADD_RAW"]))?;
+        assert!(run(Args::parse_from(["vcf_parser", "-i", &paths[0], "-i", &paths[1], "-o", path, "-c", "position,info.CADD"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_columns() -> Result<(), Box<dyn Error>> {
+        let header = ["chromosome", "info.AF", "info.CSQ.Gene", "info.CSQ.AF", "info.Pangolin.Gene", "info.CSQ.SYMBOL"].map(String::from);
+        let names = utils::output_names(&header, &[("info.CSQ.SYMBOL".to_string(), "gene".to_string())], true, true)?;
+        // a suffix is only used if no other column has it
+        let expected = [("info.CSQ.Gene", "CSQ.Gene"), ("info.CSQ.AF", "CSQ.AF"), ("info.Pangolin.Gene", "Pangolin.Gene"), ("info.AF", "info.AF"), ("info.CSQ.SYMBOL", "gene")];
+        for (column, name) in expected {
+            assert_eq!(names.get(column).map(|x| x.as_str()).unwrap_or(column), name);
+        }
+        assert!(utils::output_names(&header, &[("info.AF".to_string(), "chromosome".to_string())], false, true).is_err());
+
+        // filters and -c take the new names, which are output
+        let path = std::env::temp_dir().join("vcf_parser_test_rename.tsv");
+        let path = path.to_str().unwrap();
+        run(Args::parse_from(["vcf_parser", "-i", "test/test.vcf", "--rename", "chromosome=CHROM", "--strip-prefix", "-c", "CHROM,SYMBOL,info.CSQ.IMPACT", "-f", "IMPACT == HIGH", "-o", path]))?;
+        let data = std::fs::read_to_string(path)?;
+        let lines = data.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], "CHROM\tSYMBOL\tIMPACT");
+        assert!(lines.len() > 1 && lines[1..].iter().all(|x| x.ends_with("\tHIGH")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join("vcf_parser_test_schema.json");
+        let path = path.to_str().unwrap();
+        run(Args::parse_from(["vcf_parser", "-i", "test/test.vcf", "--fields", "CSQ,Pangolin", "--fields-join", "Feature,pangolin_transcript", "--output-format", "j", "-o", path, "schema"]))?;
+        let columns: Vec<Value> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let column = |name: &str| columns.iter().find(|x| x["name"] == name).cloned().unwrap();
+        assert_eq!(column("position")["source"], "site");
+        assert_eq!(column("info.AF"), serde_json::json!({"name": "info.AF", "source": "info", "type": "Float", "number": "A",
+            "description": "Alternate allele frequency (from /dataconnector/gnomad/V4.1/vcf/exomes/gnomad.exomes.v4.1.sites.chr1.vcf.bgz)", "inferred_type": null}));
+        let score = column("info.Pangolin.pangolin_max_score");
+        assert_eq!((&score["source"], &score["type"], &score["inferred_type"]), (&"subfield".into(), &Value::Null, &"Float".into()));
+        assert_eq!(column("info.CSQ.IMPACT")["inferred_type"], "String");
+        Ok(())
+    }
+
+    #[test]
+    fn test_config() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join("vcf_parser_test_config.yaml");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "fields: [CSQ, Pangolin]\nfields-join: [Feature, pangolin_transcript]\nstrip_prefix: true\nrename: {chromosome: CHROM}\n\
+            presets:\n  rare_disease:\n    filter: IMPACT == HIGH\n    columns: [CHROM, SYMBOL]\n")?;
+        let args = config::parse_args(["vcf_parser", "--config", path])?;
+        assert_eq!((args.fields, args.fields_join), (vec!["CSQ".to_string(), "Pangolin".to_string()], Some(vec!["Feature".to_string(), "pangolin_transcript".to_string()])));
+        assert!(args.strip_prefix && args.filter.is_none());
+        assert_eq!(args.rename, vec!["chromosome=CHROM"]);
+        // presets override the file, and the command line overrides both
+        let args = config::parse_args(["vcf_parser", "--config", path, "--preset", "rare_disease", "-c", "CHROM", "stats"])?;
+        assert_eq!((args.filter.as_deref(), args.columns), (Some("IMPACT == HIGH"), Some(vec!["CHROM".to_string()])));
+        assert!(matches!(args.command, Some(Command::Stats(_))));
+        assert!(config::parse_args(["vcf_parser", "--config", path, "--preset", "other"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_library() -> Result<(), Box<dyn Error>> {
+        // rare_damaging is test/filter.yml, from named filters of two files
+        let output = |filter: &str, path: &str| -> Result<String, Box<dyn Error>> {
+            let path = std::env::temp_dir().join(path);
+            let path = path.to_str().unwrap();
+            run(Args::parse_from(["vcf_parser", "-i", "test/test.vcf", "--fields", "CSQ,Pangolin", "--fields-join", "Feature,pangolin_transcript",
+                "--filter-library", "test/filter_library.yml", "-f", filter, "-c", "chromosome,position,info.CSQ.Feature", "-o", path]))?;
+            Ok(std::fs::read_to_string(path)?)
+        };
+        let expected = output("test/filter.yml", "vcf_parser_test_filter_file.tsv")?;
+        assert!(expected.lines().count() > 1);
+        assert_eq!(output("@rare_damaging", "vcf_parser_test_filter_library.tsv")?, expected);
+        assert!(output("@unknown", "vcf_parser_test_filter_unknown.tsv").is_err());
+
+        let path = std::env::temp_dir().join("vcf_parser_test_filter_cycle.yml");
+        std::fs::write(&path, "filters:\n  a: b OR info.AF < 0.01\n  b: info.AC > 1 AND a\n")?;
+        let mut library = filter_library::FilterLibrary::default();
+        library.load(path.to_str().unwrap())?;
+        assert!(library.get("a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_lookups() -> Result<(), Box<dyn Error>> {
+        let positions = |filter: &str| -> Result<Vec<String>, Box<dyn Error>> {
+            let path = std::env::temp_dir().join("vcf_parser_test_lookups.tsv");
+            let path = path.to_str().unwrap();
+            run(Args::parse_from(["vcf_parser", "-i", "test/test.vcf", "-c", "position", "-f", filter, "-o", path]))?;
+            let mut positions = std::fs::read_to_string(path)?.lines().skip(1).map(String::from).collect::<Vec<String>>();
+            positions.dedup();
+            Ok(positions)
+        };
+        assert_eq!(positions("info.CSQ.SYMBOL in @file:test/genes.txt")?, ["187380676", "187385546", "187385586", "187385587"]);
+        assert_eq!(positions("variant in @file:test/known_variants.tsv")?, ["220796686", "187385586"]);
+        // BED intervals are 0-based and half open, and the variant has to be inside one
+        assert_eq!(positions("variant within @bed:test/panel.bed")?, ["220796686", "187380676", "187385546", "187385586"]);
+        assert!(positions("variant within chr1").is_err());
+
+        let intervals = lookup::Lookups::default().intervals("test/panel.bed")?;
+        assert!(intervals.contains("chr2", 187380001, 187385586) && !intervals.contains("chr2", 187380000, 187380001));
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_record() {
+        // errors give the line in the file, also for lines read ahead by --fields auto
+        for fields in ["CSQ", "auto"] {
+            let args = Args::parse_from(["vcf_parser", "-i", "test/test_malformed.vcf", "--fields", fields, "stats"]);
+            let error = run(args).unwrap_err().to_string();
+            assert!(error.starts_with("Malformed record at line 59"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_projection() -> Result<(), Box<dyn Error>> {
+        let filter = parser::parse_logic_expr("info.CADD_PHRED >= 20 and pangolin_abs > 0")?;
+        let derived = vec![derive::DerivedColumn::parse("pangolin_abs=abs(info.Pangolin.pangolin_max_score)")?];
+        let options = VcfParserOptions { derived, ..Default::default() };
+        let columns = Some(vec!["position".to_string(), "info.AF".to_string()]);
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test.vcf")?));
+        let mut vcf_parser = VcfParser::new(filter, vec!["CSQ".to_string()], Some(vec!["Feature".to_string()]), options, columns, OutputFormat::T, reader)?;
+        let projection = vcf_parser.projection.clone().unwrap();
+        let mut info = projection.info.iter().map(|x| x.as_str()).collect::<Vec<&str>>();
+        info.sort();
+        assert_eq!(info, ["AF", "CADD_PHRED", "CSQ", "Pangolin"]);
+        let mut vcf_record = vcf_parser.reader.empty_record();
+        vcf_parser.reader.next_record(&mut vcf_record)?;
+        let variant = Variant::with_projection(&vcf_record, &vcf_parser.samples, &vcf_parser.csq_headers, Some(&projection));
+        assert!(variant.info.contains_key("CADD_PHRED") && !variant.info.contains_key("CADD_RAW"));
+
+        // json output has every column
+        let reader: Box<dyn BufRead + Send + Sync> = Box::new(BufReader::new(File::open("test/test.vcf")?));
+        let vcf_parser = VcfParser::new(Value::Null, vec!["CSQ".to_string()], Some(vec!["Feature".to_string()]), VcfParserOptions::default(), Some(vec!["position".to_string()]), OutputFormat::J, reader)?;
+        assert!(vcf_parser.projection.is_none());
+        Ok(())
+    }
+}
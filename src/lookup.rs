@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use crate::error::VcfParserError;
+
+/// prefix of a filter value read from a file of values, one per line, e.g. `info.CSQ.SYMBOL in @file:genes.txt`
+pub const FILE_PREFIX: &str = "@file:";
+/// prefix of a filter value read from a BED file, e.g. `variant within @bed:panel.bed`
+pub const BED_PREFIX: &str = "@bed:";
+
+/// Variants of a file, as chromosome -> position -> (reference, alternative),
+/// so a row is looked up without copying its columns
+#[derive(Debug, Default)]
+pub struct KnownVariants(HashMap<String, HashMap<u64, Vec<(String, String)>>>);
+
+impl KnownVariants {
+    pub fn contains(&self, chromosome: &str, position: u64, reference: &str, alternative: &str) -> bool {
+        self.0.get(chromosome)
+            .and_then(|x| x.get(&position))
+            .is_some_and(|x| x.iter().any(|(r, a)| r == reference && a == alternative))
+    }
+}
+
+/// Intervals of a BED file, 1-based and inclusive, sorted by start per chromosome.
+/// With the largest end of the intervals up to each one, whether an interval contains another is a binary search
+#[derive(Debug, Default)]
+pub struct Intervals(HashMap<String, (Vec<u64>, Vec<u64>)>);
+
+impl Intervals {
+    fn new(mut intervals: HashMap<String, Vec<(u64, u64)>>) -> Self {
+        Intervals(intervals.iter_mut().map(|(chromosome, x)| {
+            x.sort_unstable();
+            let starts = x.iter().map(|x| x.0).collect();
+            let max_ends = x.iter().scan(0, |max, x| {
+                *max = x.1.max(*max);
+                Some(*max)
+            }).collect();
+            (chromosome.to_string(), (starts, max_ends))
+        }).collect())
+    }
+
+    /// whether `start` to `end` is inside one of the intervals
+    pub fn contains(&self, chromosome: &str, start: u64, end: u64) -> bool {
+        let Some((starts, max_ends)) = self.0.get(chromosome) else {
+            return false;
+        };
+        let ind = starts.partition_point(|x| *x <= start);
+        ind > 0 && max_ends[ind - 1] >= end
+    }
+}
+
+/// Files of values, variants and intervals used by filters, read once and shared by the parsers of every input
+#[derive(Debug, Default, Clone)]
+pub struct Lookups {
+    values: Arc<Mutex<HashMap<String, Arc<HashSet<String>>>>>,
+    variants: Arc<Mutex<HashMap<String, Arc<KnownVariants>>>>,
+    intervals: Arc<Mutex<HashMap<String, Arc<Intervals>>>>,
+}
+
+/// lines of a file, without blank lines and `#` comments
+fn read_lines(path: &str) -> Result<Vec<String>, VcfParserError> {
+    let data = std::fs::read_to_string(path).map_err(|e| VcfParserError::InvalidFilter(format!("can't read {}: {}", path, e)))?;
+    Ok(data.lines().map(|x| x.trim_end()).filter(|x| !x.trim().is_empty() && !x.starts_with('#')).map(String::from).collect())
+}
+
+fn cached<T>(cache: &Mutex<HashMap<String, Arc<T>>>, path: &str, load: fn(&str) -> Result<T, VcfParserError>) -> Result<Arc<T>, VcfParserError> {
+    let mut cache = cache.lock().unwrap();
+    if let Some(x) = cache.get(path) {
+        return Ok(x.clone());
+    }
+    let loaded = Arc::new(load(path)?);
+    cache.insert(path.to_string(), loaded.clone());
+    Ok(loaded)
+}
+
+impl Lookups {
+    /// values of a file, one per line
+    pub fn values(&self, path: &str) -> Result<Arc<HashSet<String>>, VcfParserError> {
+        cached(&self.values, path, |path| Ok(read_lines(path)?.into_iter().map(|x| x.trim().to_string()).collect()))
+    }
+
+    /// variants of a tab separated file of chromosome, position, reference and alternative. A header line is skipped
+    pub fn variants(&self, path: &str) -> Result<Arc<KnownVariants>, VcfParserError> {
+        cached(&self.variants, path, |path| {
+            let mut variants = KnownVariants::default();
+            for (ind, line) in read_lines(path)?.iter().enumerate() {
+                let fields = line.split('\t').map(|x| x.trim()).collect::<Vec<&str>>();
+                let position = fields.get(1).and_then(|x| x.parse::<u64>().ok());
+                let (Some(position), [chromosome, _, reference, alternative, ..]) = (position, fields.as_slice()) else {
+                    if ind == 0 {
+                        continue;
+                    }
+                    return Err(VcfParserError::InvalidFilter(format!("{} should have chromosome, position, reference and alternative columns: {}", path, line)));
+                };
+                variants.0.entry(chromosome.to_string()).or_default().entry(position).or_default().push((reference.to_string(), alternative.to_string()));
+            }
+            Ok(variants)
+        })
+    }
+
+    /// intervals of a BED file, whose starts are 0-based and ends exclusive
+    pub fn intervals(&self, path: &str) -> Result<Arc<Intervals>, VcfParserError> {
+        cached(&self.intervals, path, |path| {
+            let mut intervals = HashMap::<String, Vec<(u64, u64)>>::new();
+            for line in read_lines(path)? {
+                if line.starts_with("track") || line.starts_with("browser") {
+                    continue;
+                }
+                let fields = line.split('\t').collect::<Vec<&str>>();
+                let interval = match fields.as_slice() {
+                    [chromosome, start, end, ..] => start.trim().parse::<u64>().ok().zip(end.trim().parse::<u64>().ok()).map(|x| (chromosome, x)),
+                    _ => None,
+                };
+                let Some((chromosome, (start, end))) = interval.filter(|(_, (start, end))| start < end) else {
+                    return Err(VcfParserError::InvalidFilter(format!("{} should have chromosome, start and end columns: {}", path, line)));
+                };
+                intervals.entry(chromosome.to_string()).or_default().push((start + 1, end));
+            }
+            Ok(Intervals::new(intervals))
+        })
+    }
+}
@@ -1,9 +1,7 @@
 // Read from stdin or a .vcf[.gz] file
-use clap::Parser;
-use vcf_parser::Args;
 fn main()  {
-    let args = Args::parse();
-    if let Err(e) = vcf_parser::run(args) {
+    let result = vcf_parser::config::parse_args(std::env::args_os()).and_then(vcf_parser::run);
+    if let Err(e) = result {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
@@ -0,0 +1,215 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+use flate2::write::GzEncoder;
+use crate::bgzf::BgzfWriter;
+use crate::error::VcfParserError;
+use crate::index::{IndexColumns, IndexFormat, Indexer};
+
+/// buffer between the output and the file or stdout
+const BUFFER_SIZE: usize = 1 << 20;
+/// formatted rows a worker collects before handing them to the writer thread
+const BATCH_SIZE: usize = 1 << 16;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    /// single threaded gzip
+    Gzip,
+    /// blocked gzip as bgzip writes it, which gzip can read and tabix can index
+    Bgzf,
+    Zstd,
+}
+
+impl Compression {
+    /// compression for an output path, from its extension. `.gz` is written as BGZF
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".gz") || path.ends_with(".bgz") {
+            Compression::Bgzf
+        } else if path.ends_with(".zst") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// compression of an output, `compression` if given, or else from the extension of its path
+    pub fn of_output(path: Option<&str>, compression: Option<Compression>) -> Self {
+        compression.unwrap_or_else(|| path.map(Compression::from_path).unwrap_or(Compression::None))
+    }
+}
+
+enum Sink {
+    Plain(Box<dyn Write + Send>),
+    Gzip(GzEncoder<Box<dyn Write + Send>>),
+    Bgzf(BgzfWriter<Box<dyn Write + Send>>),
+    Zstd(zstd::stream::write::Encoder<'static, Box<dyn Write + Send>>),
+}
+
+/// Where the output goes: stdout or a file, compressed or not, and optionally indexed
+pub struct Output {
+    sink: Sink,
+    /// uncompressed bytes written so far
+    written: u64,
+    /// index of the output, and the path to write it to
+    index: Option<(Indexer, String)>,
+}
+
+impl Output {
+    /// write to `path`, or stdout if None. Compression is taken from the extension of the path if not given.
+    /// `threads` compress BGZF and zstd, 0 to use all available
+    pub fn new(path: Option<&str>, compression: Option<Compression>, threads: usize) -> Result<Self, VcfParserError> {
+        let compression = Compression::of_output(path, compression);
+        let writer: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(BufWriter::with_capacity(BUFFER_SIZE, File::create(path)?)),
+            None => Box::new(BufWriter::with_capacity(BUFFER_SIZE, io::stdout())),
+        };
+        let sink = match compression {
+            Compression::None => Sink::Plain(writer),
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Bgzf => Sink::Bgzf(BgzfWriter::new(writer, threads)?),
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, 0)?;
+                let threads = if threads == 0 { std::thread::available_parallelism().map(|x| x.get()).unwrap_or(1) } else { threads };
+                encoder.multithread(threads as u32)?;
+                Sink::Zstd(encoder)
+            },
+        };
+        Ok(Output { sink, written: 0, index: None })
+    }
+
+    /// index the output as it is written, to `path`. It has to be BGZF compressed
+    pub fn index(&mut self, format: IndexFormat, columns: IndexColumns, path: &str) -> Result<(), VcfParserError> {
+        if !matches!(self.sink, Sink::Bgzf(_)) {
+            return Err(VcfParserError::InvalidArgument("only BGZF output can be indexed, e.g. --output out.tsv.gz".into()));
+        }
+        self.index = Some((Indexer::new(format, columns), path.to_string()));
+        Ok(())
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.index.is_some()
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), VcfParserError> {
+        let result = match &mut self.sink {
+            Sink::Plain(x) => x.write_all(data),
+            Sink::Gzip(x) => x.write_all(data),
+            Sink::Bgzf(x) => x.write_all(data),
+            Sink::Zstd(x) => x.write_all(data),
+        };
+        exit_if_closed(result)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<(), VcfParserError> {
+        let start = self.written;
+        self.write_all(line.as_bytes())?;
+        self.write_all(b"\n")?;
+        if let Some((indexer, _)) = &mut self.index {
+            indexer.add(line, start, self.written)?;
+        }
+        Ok(())
+    }
+
+    /// write lines formatted by a `Batch`
+    fn write_batch(&mut self, batch: &[u8]) -> Result<(), VcfParserError> {
+        if self.index.is_none() {
+            return self.write_all(batch);
+        }
+        for line in batch.strip_suffix(b"\n").unwrap_or(batch).split(|x| *x == b'\n') {
+            self.write_line(&String::from_utf8_lossy(line))?;
+        }
+        Ok(())
+    }
+
+    /// move the output to a thread of its own, which writes the batches sent to it in the order they arrive
+    pub fn spawn(self, queued_batches: usize) -> Writer {
+        let (sender, receiver) = sync_channel::<Vec<u8>>(queued_batches);
+        let handle = thread::spawn(move || {
+            let mut output = self;
+            for batch in receiver {
+                output.write_batch(&batch)?;
+            }
+            Ok(output)
+        });
+        Writer { sender, handle }
+    }
+
+    /// finish compressing, and write the index if any
+    pub fn finish(self) -> Result<(), VcfParserError> {
+        match self.sink {
+            Sink::Plain(mut x) => exit_if_closed(x.flush())?,
+            Sink::Gzip(x) => exit_if_closed(x.finish().and_then(|mut x| x.flush()))?,
+            Sink::Bgzf(mut x) => {
+                exit_if_closed(x.finish())?;
+                if let Some((indexer, path)) = &self.index {
+                    indexer.write(path, &|offset| x.virtual_offset(offset))?;
+                }
+            },
+            Sink::Zstd(x) => exit_if_closed(x.finish().and_then(|mut x| x.flush()))?,
+        }
+        Ok(())
+    }
+}
+
+/// exit quietly if stdout was closed by e.g. `| head`. Output is buffered, so this can happen on any write or flush
+fn exit_if_closed(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => std::process::exit(0),
+        x => x,
+    }
+}
+
+/// The writer thread of an `Output`
+pub struct Writer {
+    sender: SyncSender<Vec<u8>>,
+    handle: JoinHandle<Result<Output, VcfParserError>>,
+}
+
+impl Writer {
+    /// a buffer for a worker to format rows into
+    pub fn batch(&self) -> Batch {
+        Batch { buffer: Vec::with_capacity(BATCH_SIZE), sender: self.sender.clone() }
+    }
+
+    /// wait for every batch to be written, and get the output back
+    pub fn join(self) -> Result<Output, VcfParserError> {
+        drop(self.sender);
+        self.handle.join().map_err(|_| io::Error::other("the writer thread panicked"))?
+    }
+}
+
+/// Rows formatted by one worker. They are sent to the writer thread once the buffer is full, and when it is dropped
+pub struct Batch {
+    buffer: Vec<u8>,
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Batch {
+    pub fn write_line(&mut self, line: &str) -> Result<(), VcfParserError> {
+        self.buffer.extend_from_slice(line.as_bytes());
+        self.buffer.push(b'\n');
+        if self.buffer.len() >= BATCH_SIZE {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self) -> Result<(), VcfParserError> {
+        let batch = std::mem::replace(&mut self.buffer, Vec::with_capacity(BATCH_SIZE));
+        // the writer thread only stops early on an error, which `Writer::join` reports
+        self.sender.send(batch).map_err(|_| io::Error::other("the output was closed"))?;
+        Ok(())
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            let _ = self.send();
+        }
+    }
+}
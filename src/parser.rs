@@ -36,6 +36,8 @@ fn operator<'a>() -> Parser<'a, u8, String> {
     | seq(b"lt")
     | seq(b"le")
     | seq(b"in")
+    | seq(b"overlaps")
+    | seq(b"within")
     | seq("≥".as_bytes())
     | seq("≤".as_bytes())
 ).convert(|arg0: &[u8]| String::from_utf8(arg0.to_vec()))
@@ -68,6 +70,11 @@ fn str<'a>() -> Parser<'a, u8, String> {
     (sym(b'"') * none_of(b"\"").repeat(0..) - sym(b'"')).convert(String::from_utf8)
 }
 
+// a list from a file, such as @file:genes.txt or @bed:panel.bed
+fn file_value<'a>() -> Parser<'a, u8, String> {
+    (sym(b'@') + none_of(b" \t\r\n(),").repeat(1..)).collect().convert(|x| String::from_utf8(x.to_vec()))
+}
+
 fn bool<'a>() -> Parser<'a, u8, Value> {
     ((seq(b"t") | seq(b"T"))
         + (seq(b"r") | seq(b"R"))
@@ -95,6 +102,7 @@ fn value<'a>() -> Parser<'a, u8, Value> {
         * (real_number().map(|f| Value::from(f))
             | integer().map(|i| Value::Number(i.into()))
             | str().map(|s| Value::String(s))
+            | file_value().map(Value::String)
             | bool()
             | none().map(|_| Value::Null)
             | ident().map(|s| Value::String(s))
@@ -128,24 +136,28 @@ fn boolean_condition<'a>() -> Parser<'a, u8, Value> {
             .map(|((lval, op), rval)| json!({"name": lval, "op": op, "value": rval}))
             | (lparen() * call(boolean_expression) - rparen()).map(|boolean_expression| {
                 json!(boolean_expression)
-            }))
+            })
+            // a named filter, from a filter library
+            | ident().map(|name| json!({"ref": name})))
         - space()
 }
 
+/// a list of conditions joined by `op`, as one AND / OR with all of them
+fn join_conditions(op: &str, first: Value, rest: Vec<Value>) -> Value {
+    if rest.is_empty() {
+        return first;
+    }
+    json!({op: ([vec![first], rest].concat())})
+}
+
 fn and_expression<'a>() -> Parser<'a, u8, Value> {
-    (boolean_condition() + and() * call(boolean_condition)).map(
-        |(boolean_condition, and_expression)| json!({
-            "AND": [boolean_condition, and_expression]
-        })
-    ) | boolean_condition()
+    (boolean_condition() + (and() * call(boolean_condition)).repeat(0..))
+        .map(|(first, rest)| join_conditions("AND", first, rest))
 }
 
 fn or_expression<'a>() -> Parser<'a, u8, Value> {
-    (and_expression() + or() * call(and_expression)).map(
-        |(and_expression, or_expression)| json!({
-            "OR": [and_expression, or_expression]
-        })
-    ) | and_expression()
+    (and_expression() + (or() * call(and_expression)).repeat(0..))
+        .map(|(first, rest)| join_conditions("OR", first, rest))
 }
 
 fn boolean_expression<'a>() -> Parser<'a, u8, Value> {
@@ -156,6 +168,71 @@ fn boolean_expression<'a>() -> Parser<'a, u8, Value> {
     ) | boolean_condition()
 }
 
+// Arithmetic expressions for derived columns. They are parsed into a json tree, like filters:
+// {"col": name} for a column, {"lit": value} for a literal, {"op": op, "args": [..]} for operators
+// and {"fn": name, "args": [..]} for function calls.
+fn expr_number<'a>() -> Parser<'a, u8, Value> {
+    let frac = sym(b'.') + one_of(b"0123456789").repeat(1..);
+    let exp = one_of(b"eE") + one_of(b"+-").opt() + one_of(b"0123456789").repeat(1..);
+    (integer() + frac.opt() + exp.opt())
+        .collect()
+        .convert(str::from_utf8)
+        .map(|s| json!({"lit": crate::utils::try_parse_number(s)}))
+}
+
+fn expr_ident<'a>() -> Parser<'a, u8, Value> {
+    ident().map(|s| match s.to_lowercase().as_str() {
+        "true" => json!({"lit": true}),
+        "false" => json!({"lit": false}),
+        "none" | "null" => json!({"lit": null}),
+        _ => json!({"col": s}),
+    })
+}
+
+fn expr_primary<'a>() -> Parser<'a, u8, Value> {
+    space()
+        * ((ident() - space() - lparen() + list(call(arith_expression), sym(b',')) - space() - rparen())
+            .map(|(f, args)| json!({"fn": f.to_lowercase(), "args": args}))
+            | expr_number()
+            | str().map(|s| json!({"lit": s}))
+            | expr_ident()
+            | (lparen() * call(arith_expression) - rparen()))
+        - space()
+}
+
+fn expr_unary<'a>() -> Parser<'a, u8, Value> {
+    (space() * (sym(b'-') * call(expr_unary)).map(|x| json!({"op": "neg", "args": [x]})))
+        | expr_primary()
+}
+
+fn fold_binary(first: Value, rest: Vec<(u8, Value)>) -> Value {
+    rest.into_iter().fold(first, |acc, (op, x)| json!({"op": (op as char).to_string(), "args": [acc, x]}))
+}
+
+fn expr_term<'a>() -> Parser<'a, u8, Value> {
+    (expr_unary() + (one_of(b"*/") + expr_unary()).repeat(0..)).map(|(first, rest)| fold_binary(first, rest))
+}
+
+fn expr_additive<'a>() -> Parser<'a, u8, Value> {
+    (expr_term() + (one_of(b"+-") + expr_term()).repeat(0..)).map(|(first, rest)| fold_binary(first, rest))
+}
+
+fn comparison_operator<'a>() -> Parser<'a, u8, String> {
+    (seq(b"==") | seq(b"!=") | seq(b"<=") | seq(b">=") | seq(b"=") | seq(b"<") | seq(b">"))
+        .convert(|arg0: &[u8]| String::from_utf8(arg0.to_vec()))
+}
+
+fn arith_expression<'a>() -> Parser<'a, u8, Value> {
+    (expr_additive() + (comparison_operator() + expr_additive()).opt()).map(|(lhs, cmp)| match cmp {
+        Some((op, rhs)) => json!({"op": op, "args": [lhs, rhs]}),
+        None => lhs,
+    })
+}
+
+pub fn parse_arith_expr(input: &str) -> Result<Value, pom::Error> {
+    (space() * arith_expression() - end()).parse(input.as_bytes())
+}
+
 // Define the main parser function
 pub fn parse_logic_expr<'a>(input: &str) -> Result<Value, pom::Error> {
     //(space() * boolean_expression() - end()).parse(input.as_bytes())
@@ -173,6 +250,9 @@ mod tests {
             (r#"(foo == bar or baz > 10) AND baz <= 5"#, r#"{"AND":[{"OR":[{"name":"foo","op":"==","value":"bar"},{"name":"baz","op":">","value":10.0}]},{"name":"baz","op":"<=","value":5.0}]}"#),
             (r#"baz <= 5 AND (foo == bar or baz > 10)"#, r#"{"AND":[{"name":"baz","op":"<=","value":5.0},{"OR":[{"name":"foo","op":"==","value":"bar"},{"name":"baz","op":">","value":10.0}]}]}"#),
             (r#"foo == bar AND baz > 10 Or baz <= 5"#, r#"{"OR":[{"AND":[{"name":"foo","op":"==","value":"bar"},{"name":"baz","op":">","value":10.0}]},{"name":"baz","op":"<=","value":5.0}]}"#),
+            (r#"foo = bar AND baz > 10 and qux < 1 or foo = baz"#, r#"{"OR":[{"AND":[{"name":"foo","op":"=","value":"bar"},{"name":"baz","op":">","value":10.0},{"name":"qux","op":"<","value":1.0}]},{"name":"foo","op":"=","value":"baz"}]}"#),
+            (r#"info.CSQ.SYMBOL in @file:test/genes.txt and variant within @bed:panel.bed"#, r#"{"AND":[{"name":"info.CSQ.SYMBOL","op":"in","value":"@file:test/genes.txt"},{"name":"variant","op":"within","value":"@bed:panel.bed"}]}"#),
+            (r#"rare AND (damaging or baz > 10)"#, r#"{"AND":[{"ref":"rare"},{"OR":[{"ref":"damaging"},{"name":"baz","op":">","value":10.0}]}]}"#),
             (r#"baz <= 5 or foo == bar AND baz > 10  "#, r#"{"OR":[{"name":"baz","op":"<=","value":5.0},{"AND":[{"name":"foo","op":"==","value":"bar"},{"name":"baz","op":">","value":10.0}]}]}"#),
         ];
         for (expr, expected) in exprs.iter() {
@@ -182,4 +262,19 @@ mod tests {
         }
         Ok(())
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_arith() -> Result<(), pom::Error> {
+        let exprs = [
+            (r#"info.AC + 1"#, r#"{"op":"+","args":[{"col":"info.AC"},{"lit":1}]}"#),
+            (r#"1 - 2 * x"#, r#"{"op":"-","args":[{"lit":1},{"op":"*","args":[{"lit":2},{"col":"x"}]}]}"#),
+            (r#"max(a, -b) / 2.5"#, r#"{"op":"/","args":[{"fn":"max","args":[{"col":"a"},{"op":"neg","args":[{"col":"b"}]}]},{"lit":2.5}]}"#),
+            (r#"if(a >= 0.5, "high", none)"#, r#"{"fn":"if","args":[{"op":">=","args":[{"col":"a"},{"lit":0.5}]},{"lit":"high"},{"lit":null}]}"#),
+            (r#"(a - b) * c"#, r#"{"op":"*","args":[{"op":"-","args":[{"col":"a"},{"col":"b"}]},{"col":"c"}]}"#),
+        ];
+        for (expr, expected) in exprs.iter() {
+            let result = parse_arith_expr(expr)?;
+            assert_eq!(result, serde_json::from_str::<serde_json::Value>(expected).unwrap());
+        }
+        Ok(())
+    }
+}
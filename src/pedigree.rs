@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use serde_json::{json, Map, Value};
+use vcf::VCFRecord;
+use crate::error::VcfParserError;
+use crate::utils;
+
+/// inheritance models a variant can fit, as reported in the `inheritance` column
+pub const DE_NOVO: &str = "de_novo";
+pub const AR_HOM: &str = "ar_hom";
+pub const AD: &str = "ad";
+pub const X_LINKED: &str = "x_linked";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sex {
+    Male,
+    Female,
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct Individual {
+    pub family: String,
+    pub id: String,
+    pub father: Option<String>,
+    pub mother: Option<String>,
+    pub sex: Sex,
+    /// None if the phenotype is missing
+    pub affected: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Call {
+    HomRef,
+    Het,
+    /// homozygous alt, or hemizygous alt on a male sex chromosome
+    HomAlt,
+}
+
+/// parent a heterozygous variant was inherited from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Paternal,
+    Maternal,
+    /// both or neither parent carries it, or a parent is not called
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Chromosome {
+    Autosome,
+    X,
+    Y,
+}
+
+/// Families read from a PED file, used to annotate the inheritance models each variant fits
+#[derive(Debug, Clone)]
+pub struct Pedigree {
+    pub individuals: Vec<Individual>,
+    /// parents' genotypes with a lower GQ are not trusted. Missing GQ passes
+    pub parent_min_gq: i64,
+    /// parents' genotypes with a lower DP are not trusted. Missing DP passes
+    pub parent_min_dp: i64,
+}
+
+impl Pedigree {
+    pub fn from_file(path: &str, parent_min_gq: i64, parent_min_dp: i64) -> Result<Self, VcfParserError> {
+        // family, individual, father, mother, sex (1=male, 2=female), phenotype (1=unaffected, 2=affected)
+        let mut individuals = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let cols = line.split_whitespace().collect::<Vec<&str>>();
+            if cols.len() < 6 {
+                return Err(VcfParserError::InvalidArgument(format!("PED line should have 6 columns: {}", line)));
+            }
+            let parent = |x: &str| if x == "0" || x == "." { None } else { Some(x.to_string()) };
+            individuals.push(Individual {
+                family: cols[0].to_string(),
+                id: cols[1].to_string(),
+                father: parent(cols[2]),
+                mother: parent(cols[3]),
+                sex: match cols[4] {
+                    "1" => Sex::Male,
+                    "2" => Sex::Female,
+                    _ => Sex::Unknown,
+                },
+                affected: match cols[5] {
+                    "1" => Some(false),
+                    "2" => Some(true),
+                    _ => None,
+                },
+            });
+        }
+        Ok(Pedigree { individuals, parent_min_gq, parent_min_dp })
+    }
+
+    /// an individual of a family. IDs are only unique within a family, as parents are in the child's family
+    fn individual(&self, family: &str, id: &str) -> Option<&Individual> {
+        self.individuals.iter().find(|x| x.family == family && x.id == id)
+    }
+
+    /// GT, GQ and DP of every PED member in the VCF, decoded from the record rather than the output samples.
+    /// Members are named by their output names, as renamed by `sample_names`
+    pub fn record_genotype(&self, vcf_record: &VCFRecord, sample_names: &HashMap<String, String>) -> Map<String, Value> {
+        vcf_record.header().samples().iter().filter_map(|sample| {
+            let name = String::from_utf8_lossy(sample).to_string();
+            let name = sample_names.get(&name).cloned().unwrap_or(name);
+            if !self.individuals.iter().any(|x| x.id == name) {
+                return None;
+            }
+            let field = |key: &[u8]| vcf_record.genotype(sample, key).and_then(|x| x.first()).map(|x| String::from_utf8_lossy(x).to_string());
+            let number = |key: &[u8]| field(key).and_then(|x| x.parse::<i64>().ok());
+            Some((name, json!({"GT": field(b"GT"), "GQ": number(b"GQ"), "DP": number(b"DP")})))
+        }).collect()
+    }
+
+    /// inheritance models fitting any family, given the genotypes of a variant (sample -> FORMAT fields)
+    pub fn inheritance(&self, chromosome: &str, genotype: &Map<String, Value>) -> Vec<String> {
+        let chromosome = match chromosome.trim_start_matches("chr") {
+            "X" | "x" | "23" => Chromosome::X,
+            "Y" | "y" | "24" => Chromosome::Y,
+            "M" | "MT" => return vec![],
+            _ => Chromosome::Autosome,
+        };
+        let mut families = self.individuals.iter().map(|x| x.family.as_str()).collect::<Vec<&str>>();
+        families.sort_unstable();
+        families.dedup();
+        let mut models: Vec<String> = Vec::new();
+        for family in families {
+            let members = self.individuals.iter()
+                .filter(|x| x.family == family && genotype.contains_key(&x.id))
+                .collect::<Vec<&Individual>>();
+            for model in [DE_NOVO, AR_HOM, AD, X_LINKED] {
+                if !models.iter().any(|x| x == model) && self.fits(model, chromosome, &members, genotype) {
+                    models.push(model.to_string());
+                }
+            }
+        }
+        models
+    }
+
+    /// affected individuals, the probands of compound heterozygote detection
+    pub fn affected(&self) -> impl Iterator<Item = &Individual> {
+        self.individuals.iter().filter(|x| x.affected == Some(true))
+    }
+
+    /// whether an individual is heterozygous for the variant
+    pub fn is_het(&self, individual: &Individual, genotype: &Map<String, Value>) -> bool {
+        genotype_call(individual, genotype) == Some(Call::Het)
+    }
+
+    /// which parent a child's variant comes from, using the parents' trusted calls
+    pub fn phase(&self, child: &Individual, genotype: &Map<String, Value>) -> Phase {
+        let father = self.parent_call(&child.family, &child.father, genotype);
+        let mother = self.parent_call(&child.family, &child.mother, genotype);
+        match (father, mother) {
+            (father, Some(Call::HomRef)) if carries(father) => Phase::Paternal,
+            (Some(Call::HomRef), mother) if carries(mother) => Phase::Maternal,
+            _ => Phase::Unknown,
+        }
+    }
+
+    fn parent_call(&self, family: &str, id: &Option<String>, genotype: &Map<String, Value>) -> Option<Call> {
+        // a trusted call of a parent, None if missing or below the thresholds
+        let parent = self.individual(family, id.as_deref()?)?;
+        let fields = genotype.get(&parent.id)?;
+        let passes = |key: &str, min: i64| fields.get(key).and_then(|x| x.as_i64()).is_none_or(|x| x >= min);
+        if passes("GQ", self.parent_min_gq) && passes("DP", self.parent_min_dp) {
+            genotype_call(parent, genotype)
+        } else {
+            None
+        }
+    }
+
+    fn fits(&self, model: &str, chromosome: Chromosome, members: &[&Individual], genotype: &Map<String, Value>) -> bool {
+        let affected = members.iter().filter(|x| x.affected == Some(true)).collect::<Vec<_>>();
+        let unaffected = members.iter().filter(|x| x.affected == Some(false)).collect::<Vec<_>>();
+        let call = |x: &Individual| genotype_call(x, genotype);
+        let parent_call = |child: &Individual, id: &Option<String>| self.parent_call(&child.family, id, genotype);
+        if affected.is_empty() {
+            return false;
+        }
+        match model {
+            DE_NOVO => affected.iter().any(|child| {
+                let (father, mother) = (parent_call(child, &child.father), parent_call(child, &child.mother));
+                // males inherit their X from the mother and their Y from the father
+                let parents = match (chromosome, child.sex) {
+                    (Chromosome::X, Sex::Male) => vec![mother],
+                    (Chromosome::Y, _) => vec![father],
+                    _ => vec![father, mother],
+                };
+                carries(call(child)) && parents.iter().all(|x| *x == Some(Call::HomRef))
+            }),
+            AR_HOM => {
+                chromosome == Chromosome::Autosome
+                    && affected.iter().all(|x| call(x) == Some(Call::HomAlt))
+                    && unaffected.iter().all(|x| call(x) != Some(Call::HomAlt))
+                    // parents of the affected are carriers, unless not called
+                    && affected.iter().all(|x| [&x.father, &x.mother].iter().all(|p| !matches!(parent_call(x, p), Some(Call::HomRef))))
+            },
+            AD => {
+                chromosome == Chromosome::Autosome
+                    && affected.iter().all(|x| carries(call(x)))
+                    && unaffected.iter().all(|x| !carries(call(x)))
+            },
+            X_LINKED => {
+                chromosome == Chromosome::X
+                    && affected.iter().all(|x| match x.sex {
+                        Sex::Male => carries(call(x)),
+                        _ => call(x) == Some(Call::HomAlt),
+                    })
+                    && unaffected.iter().all(|x| match x.sex {
+                        Sex::Male => !carries(call(x)),
+                        _ => call(x) != Some(Call::HomAlt),
+                    })
+            },
+            _ => false,
+        }
+    }
+}
+
+fn carries(call: Option<Call>) -> bool {
+    matches!(call, Some(Call::Het) | Some(Call::HomAlt))
+}
+
+fn genotype_call(individual: &Individual, genotype: &Map<String, Value>) -> Option<Call> {
+    // call of an individual from its GT, None if missing
+    let gt = genotype.get(&individual.id)?.get("GT")?.as_str()?;
+    let (alt, ploidy) = utils::alt_allele_count(gt)?;
+    Some(match alt {
+        0 => Call::HomRef,
+        x if x == ploidy => Call::HomAlt,
+        _ => Call::Het,
+    })
+}
@@ -0,0 +1,480 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use serde_json::{Map, Value};
+use crate::utils::{JoinOptions, Region};
+use crate::variant::Variant;
+use crate::JoinType;
+
+/// A typed value of a row. Strings are borrowed from the parsed variant where possible
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Cow<'a, str>),
+    /// subfield of an unexploded nested field, or a list column such as inheritance
+    List(Vec<Cell<'a>>),
+    /// FORMAT fields of a sample
+    Json(Cow<'a, Value>),
+}
+
+impl<'a> Cell<'a> {
+    pub fn from_value(value: &'a Value) -> Self {
+        match value {
+            Value::Null => Cell::Null,
+            Value::Bool(x) => Cell::Bool(*x),
+            Value::Number(x) => match x.as_i64() {
+                Some(x) => Cell::Int(x),
+                None => Cell::Float(x.as_f64().unwrap_or(f64::NAN)),
+            },
+            Value::String(x) => Cell::Str(Cow::Borrowed(x)),
+            Value::Array(arr) => Cell::List(arr.iter().map(Cell::from_value).collect()),
+            Value::Object(_) => Cell::Json(Cow::Borrowed(value)),
+        }
+    }
+
+    /// a cell that owns its data, e.g. a filter constant or a derived value
+    pub fn owned(value: Value) -> Cell<'static> {
+        match value {
+            Value::String(x) => Cell::Str(Cow::Owned(x)),
+            Value::Array(arr) => Cell::List(arr.into_iter().map(Cell::owned).collect()),
+            Value::Object(_) => Cell::Json(Cow::Owned(value)),
+            x => Cell::from_value(&x).into_static(),
+        }
+    }
+
+    fn into_static(self) -> Cell<'static> {
+        match self {
+            Cell::Null => Cell::Null,
+            Cell::Bool(x) => Cell::Bool(x),
+            Cell::Int(x) => Cell::Int(x),
+            Cell::Float(x) => Cell::Float(x),
+            Cell::Str(x) => Cell::Str(Cow::Owned(x.into_owned())),
+            Cell::List(x) => Cell::List(x.into_iter().map(Cell::into_static).collect()),
+            Cell::Json(x) => Cell::Json(Cow::Owned(x.into_owned())),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            Cell::Null => Value::Null,
+            Cell::Bool(x) => Value::Bool(*x),
+            Cell::Int(x) => Value::from(*x),
+            // NaN and infinity have no json number
+            Cell::Float(x) => serde_json::Number::from_f64(*x).map(Value::Number).unwrap_or(Value::Null),
+            Cell::Str(x) => Value::String(x.to_string()),
+            Cell::List(x) => Value::Array(x.iter().map(Cell::to_value).collect()),
+            Cell::Json(x) => x.clone().into_owned(),
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Cell::Int(x) => Some(*x as f64),
+            Cell::Float(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Cell::Str(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// the elements of a list, such as the values of an unexploded subfield, or else the cell itself
+    pub fn elements(&self) -> &[Cell<'a>] {
+        match self {
+            Cell::List(x) => x,
+            x => std::slice::from_ref(x),
+        }
+    }
+
+    /// equality as used by filters, where 0 equals 0.0
+    pub fn equals(&self, other: &Cell) -> bool {
+        match (self, other) {
+            (Cell::Int(_) | Cell::Float(_), Cell::Int(_) | Cell::Float(_)) => self.as_f64() == other.as_f64(),
+            (Cell::List(x), Cell::List(y)) => x.len() == y.len() && x.iter().zip(y).all(|(x, y)| x.equals(y)),
+            (Cell::Str(x), Cell::Str(y)) => x == y,
+            (Cell::Json(x), Cell::Json(y)) => x == y,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other) && self == other,
+        }
+    }
+
+    /// text of the cell in tsv output. Numbers are written as serde_json does, e.g. 34.0 for a float
+    pub fn to_tsv(&self) -> String {
+        match self {
+            Cell::Null => String::new(),
+            Cell::Str(x) => x.to_string(),
+            Cell::Int(x) => x.to_string(),
+            x => x.to_value().to_string(),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Cell<'a> {
+    fn from(x: &'a str) -> Self {
+        Cell::Str(Cow::Borrowed(x))
+    }
+}
+
+impl<'a, T: Into<Cell<'a>>> From<Option<T>> for Cell<'a> {
+    fn from(x: Option<T>) -> Self {
+        x.map(Into::into).unwrap_or(Cell::Null)
+    }
+}
+
+impl<'a> From<i64> for Cell<'a> {
+    fn from(x: i64) -> Self {
+        Cell::Int(x)
+    }
+}
+
+impl<'a> From<u64> for Cell<'a> {
+    fn from(x: u64) -> Self {
+        Cell::Int(x as i64)
+    }
+}
+
+impl<'a> From<f64> for Cell<'a> {
+    fn from(x: f64) -> Self {
+        Cell::Float(x)
+    }
+}
+
+impl<'a> From<bool> for Cell<'a> {
+    fn from(x: bool) -> Self {
+        Cell::Bool(x)
+    }
+}
+
+/// A row of cells indexed by column id. A None cell is a column the row does not have,
+/// which is left out of json output, while Some(Cell::Null) is written as null
+#[derive(Clone, Debug)]
+pub struct Row<'a> {
+    pub cells: Vec<Option<Cell<'a>>>,
+    /// whether nested fields were exploded into one row per element, or kept as lists
+    pub exploded: bool,
+}
+
+impl Row<'_> {
+    /// a row that owns its cells, to keep once its variant is gone
+    pub fn into_static(self) -> Row<'static> {
+        Row { cells: self.cells.into_iter().map(|x| x.map(Cell::into_static)).collect(), exploded: self.exploded }
+    }
+}
+
+/// Column names interned to ids, with the nested fields and samples among them
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    /// nested INFO field, e.g. info.CSQ -> its subfields and their column ids
+    nested: Vec<(String, Vec<(String, usize)>)>,
+    /// output sample name -> column id
+    samples: Vec<(String, usize)>,
+}
+
+impl Schema {
+    /// `columns` should be every column a row can have, as given by `utils::get_output_header`
+    pub fn new(columns: &[String], nested: &HashMap<String, Vec<String>>, samples: &[String]) -> Self {
+        let mut schema = Schema::default();
+        for column in columns {
+            schema.intern(column);
+        }
+        let mut nested = nested.iter().collect::<Vec<(&String, &Vec<String>)>>();
+        nested.sort();
+        for (field, subfields) in nested {
+            let field = format!("info.{}", field);
+            let subfields = subfields.iter()
+                .map(|x| (x.to_string(), schema.intern(&format!("{}.{}", field, x))))
+                .collect::<Vec<(String, usize)>>();
+            schema.nested.push((field, subfields));
+        }
+        schema.samples = samples.iter().map(|x| (x.to_string(), schema.intern(x))).collect();
+        schema
+    }
+
+    /// a column rows don't have values for, such as one only found in another input
+    pub fn add(&mut self, name: &str) -> usize {
+        self.intern(name)
+    }
+
+    fn intern(&mut self, name: &str) -> usize {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    pub fn id(&self, name: &str) -> Option<usize> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: usize) -> &str {
+        &self.names[id]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// whether the column is a subfield of a nested field, such as info.CSQ.IMPACT
+    pub fn is_subfield(&self, id: usize) -> bool {
+        self.nested.iter().any(|(_, subfields)| subfields.iter().any(|(_, x)| *x == id))
+    }
+
+    pub fn is_sample(&self, id: usize) -> bool {
+        self.samples.iter().any(|(_, x)| *x == id)
+    }
+
+    /// GT of each output sample of the row, None if missing
+    pub fn sample_gt<'r>(&'r self, row: &'r Row) -> impl Iterator<Item = (&'r str, Option<&'r str>)> {
+        self.samples.iter().map(|(sample, id)| {
+            let gt = match &row.cells[*id] {
+                Some(Cell::Json(x)) => x.get("GT").and_then(|x| x.as_str()),
+                _ => None,
+            };
+            (sample.as_str(), gt)
+        })
+    }
+
+    pub fn get<'r, 'a>(&self, row: &'r Row<'a>, name: &str) -> Option<&'r Cell<'a>> {
+        self.id(name).and_then(|id| row.cells[id].as_ref())
+    }
+
+    pub fn set<'a>(&self, row: &mut Row<'a>, name: &str, cell: Cell<'a>) {
+        if let Some(id) = self.id(name) {
+            row.cells[id] = Some(cell);
+        }
+    }
+
+    /// the row of a variant, with nested fields kept as lists.
+    /// `annotations` are extra site columns, such as cohort statistics and inheritance models
+    pub fn row<'a>(&self, variant: &'a Variant, annotations: &'a Map<String, Value>) -> Row<'a> {
+        let mut row = Row { cells: vec![None; self.len()], exploded: false };
+        let interval = |x: &Option<Vec<i64>>| Cell::from(x.as_ref().map(|x| Cell::List(x.iter().map(|x| Cell::Int(*x)).collect())));
+        let site: [(&str, Cell<'a>); 16] = [
+            ("chromosome", variant.chromosome.as_str().into()),
+            ("position", variant.position.into()),
+            ("id", variant.id.as_str().into()),
+            ("reference", variant.reference.as_str().into()),
+            ("alternative", variant.alternative.as_str().into()),
+            ("qual", variant.qual.into()),
+            ("filter", variant.filter.as_str().into()),
+            ("variant_type", variant.variant_type.as_str().into()),
+            ("length_change", variant.length_change.into()),
+            ("is_transition", variant.is_transition.into()),
+            ("end", variant.end.into()),
+            ("sv_type", variant.sv_type.as_deref().into()),
+            ("mate_chromosome", variant.mate_chromosome.as_deref().into()),
+            ("mate_position", variant.mate_position.into()),
+            ("cipos", interval(&variant.cipos)),
+            ("ciend", interval(&variant.ciend)),
+        ];
+        for (name, cell) in site {
+            self.set(&mut row, name, cell);
+        }
+        for (key, val) in &variant.info {
+            let name = format!("info.{}", key);
+            match (self.nested.iter().find(|(field, _)| *field == name), val) {
+                (Some((_, subfields)), Value::Array(elements)) => {
+                    for (subfield, id) in subfields {
+                        let list = elements.iter().map(|x| x.get(subfield).map(Cell::from_value).unwrap_or(Cell::Null)).collect();
+                        row.cells[*id] = Some(Cell::List(list));
+                    }
+                },
+                _ => self.set(&mut row, &name, Cell::from_value(val)),
+            }
+        }
+        for (sample, val) in &variant.genotype {
+            self.set(&mut row, sample, Cell::from_value(val));
+        }
+        for (key, val) in annotations {
+            self.set(&mut row, key, Cell::from_value(val));
+        }
+        row
+    }
+
+    /// explode each of `fields` (e.g. info.CSQ) into a table of one row per element.
+    /// The subfields of the other nested fields are dropped, to be joined back in
+    pub fn explode<'a>(&self, row: &Row<'a>, fields: &[String]) -> Vec<Vec<Row<'a>>> {
+        let mut base = row.clone();
+        base.exploded = true;
+        for (_, subfields) in self.nested.iter().filter(|(field, _)| fields.contains(field)) {
+            for (_, id) in subfields {
+                base.cells[*id] = None;
+            }
+        }
+        fields.iter().map(|field| {
+            let Some((_, subfields)) = self.nested.iter().find(|(x, _)| x == field) else {
+                return vec![];
+            };
+            let length = subfields.iter().find_map(|(_, id)| match &row.cells[*id] {
+                Some(Cell::List(x)) => Some(x.len()),
+                _ => None,
+            }).unwrap_or(0);
+            (0..length).map(|ind| {
+                let mut exploded = base.clone();
+                for (_, id) in subfields {
+                    if let Some(Cell::List(x)) = &row.cells[*id] {
+                        exploded.cells[*id] = Some(x[ind].clone());
+                    }
+                }
+                exploded
+            }).collect()
+        }).collect()
+    }
+
+    /// whether the interval of a variant (position to end), or the mate of a breakend, overlaps a region
+    pub fn overlaps(&self, row: &Row, region: &Region) -> bool {
+        let number = |name: &str| self.get(row, name).and_then(|x| x.as_f64()).map(|x| x as u64);
+        let chromosome = self.get(row, "chromosome").and_then(|x| x.as_str()).unwrap_or_default();
+        let start = number("position").unwrap_or_default();
+        let end = number("end").unwrap_or(start);
+        if region.overlaps(chromosome, start, end.max(start)) {
+            return true;
+        }
+        match (self.get(row, "mate_chromosome").and_then(|x| x.as_str()), number("mate_position")) {
+            (Some(chromosome), Some(position)) => region.overlaps(chromosome, position, position),
+            _ => false,
+        }
+    }
+
+    /// the columns of the row as tsv fields
+    pub fn tsv(&self, row: &Row, columns: &[usize]) -> Vec<String> {
+        columns.iter().map(|id| row.cells[*id].as_ref().map(Cell::to_tsv).unwrap_or_default()).collect()
+    }
+
+    /// the row as a json object, the way a variant serialises: samples under `genotype`, and unexploded
+    /// nested fields as lists of objects
+    pub fn to_map(&self, row: &Row) -> Map<String, Value> {
+        let mut map = Map::new();
+        let mut genotype = Map::new();
+        let mut skip = vec![false; self.len()];
+        for (sample, id) in &self.samples {
+            skip[*id] = true;
+            if let Some(cell) = &row.cells[*id] {
+                genotype.insert(sample.to_string(), cell.to_value());
+            }
+        }
+        for (field, subfields) in &self.nested {
+            subfields.iter().for_each(|(_, id)| skip[*id] = !row.exploded);
+            if row.exploded {
+                continue;
+            }
+            let lists = subfields.iter().filter_map(|(subfield, id)| match &row.cells[*id] {
+                Some(Cell::List(x)) => Some((subfield, x)),
+                _ => None,
+            }).collect::<Vec<(&String, &Vec<Cell>)>>();
+            if lists.is_empty() {
+                continue;
+            }
+            let elements = (0..lists[0].1.len()).map(|ind| {
+                Value::Object(lists.iter().map(|(subfield, x)| (subfield.to_string(), x[ind].to_value())).collect())
+            }).collect();
+            map.insert(field.to_string(), Value::Array(elements));
+        }
+        for (id, cell) in row.cells.iter().enumerate() {
+            if let (Some(cell), false) = (cell, skip[id]) {
+                map.insert(self.names[id].to_string(), cell.to_value());
+            }
+        }
+        map.insert("genotype".to_string(), Value::Object(genotype));
+        map
+    }
+}
+
+fn join_value(row: &Row, keys: &[Vec<usize>], ignore_version: bool) -> Option<String> {
+    // build the lookup key of a row. Each key is composite (e.g. gene + transcript).
+    // For an already joined table, the first key with every part present is used.
+    // A row without one has no key, so it never matches another row
+    keys.iter().find_map(|key| key.iter().map(|id| match row.cells[*id].as_ref().unwrap_or(&Cell::Null) {
+        Cell::Null => None,
+        Cell::Str(x) if ignore_version => Some(x.split('.').next().unwrap().to_string()),
+        x => Some(x.to_tsv()),
+    }).collect::<Option<Vec<String>>>().map(|x| x.join("\t")))
+}
+
+fn fill_from<'a>(row: &mut Row<'a>, from: Row<'a>) {
+    // take the cells of a matched row where this row has none or null
+    for (cell, other) in row.cells.iter_mut().zip(from.cells) {
+        if matches!(cell, None | Some(Cell::Null)) && other.is_some() {
+            *cell = other;
+        }
+    }
+}
+
+fn fill_null(row: &mut Row, columns: &[usize]) {
+    // rows without a match get null for the columns of the other table
+    for id in columns {
+        if row.cells[*id].is_none() {
+            row.cells[*id] = Some(Cell::Null);
+        }
+    }
+}
+
+/// join exploded tables on their key columns. Each key is composite (e.g. gene + transcript).
+/// The first table is the left-most one, so a left join keeps all of its rows.
+/// Rows sharing a key are joined with every row of that key in the other table, as in a relational join
+pub fn join_tables<'a>(mut tables: Vec<Vec<Row<'a>>>, keys: &[Vec<usize>], options: &JoinOptions) -> Vec<Row<'a>> {
+    if tables.is_empty() || tables.len() != keys.len() {
+        return vec![];
+    }
+    let present = |table: &Vec<Row>| table.first()
+        .map(|x| x.cells.iter().enumerate().filter(|(_, x)| x.is_some()).map(|(id, _)| id).collect::<Vec<usize>>())
+        .unwrap_or_default();
+    // right_table would be the joint table, built from the last table backwards
+    let mut keys = keys.to_vec();
+    let mut right_table = tables.pop().unwrap();
+    let mut right_keys = vec![keys.pop().unwrap()];
+    while let Some(left_table) = tables.pop() {
+        let left_key = keys.pop().unwrap();
+        let (left_columns, right_columns) = (present(&left_table), present(&right_table));
+        // index the left table by key
+        let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+        for (ind, left_row) in left_table.iter().enumerate() {
+            if let Some(value) = join_value(left_row, std::slice::from_ref(&left_key), options.ignore_version) {
+                index.entry(value).or_default().push(ind);
+            }
+        }
+        let mut matched = vec![false; left_table.len()];
+        let mut joined: Vec<Row> = Vec::with_capacity(right_table.len().max(left_table.len()));
+        for mut right_row in right_table {
+            let right_value = join_value(&right_row, &right_keys, options.ignore_version);
+            match right_value.and_then(|x| index.get(&x)) {
+                Some(left_indices) => {
+                    for left_index in left_indices {
+                        let mut row = right_row.clone();
+                        fill_from(&mut row, left_table[*left_index].clone());
+                        matched[*left_index] = true;
+                        joined.push(row);
+                    }
+                },
+                // right rows without a match are only kept by an outer join
+                None if options.join_type == JoinType::Outer => {
+                    fill_null(&mut right_row, &left_columns);
+                    joined.push(right_row);
+                },
+                None => (),
+            }
+        }
+        if options.join_type != JoinType::Inner {
+            for (mut left_row, _) in left_table.into_iter().zip(matched).filter(|(_, matched)| !matched) {
+                fill_null(&mut left_row, &right_columns);
+                joined.push(left_row);
+            }
+        }
+        right_keys.insert(0, left_key);
+        right_table = joined;
+    }
+    right_table
+}
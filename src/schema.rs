@@ -0,0 +1,174 @@
+use serde_json::{json, Value};
+use vcf::{Number, ValueType, VCFHeader};
+use crate::row::{Cell, Row, Schema};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct SchemaArgs {
+    /// records to read to infer the types of nested subfields and computed columns
+    #[arg(long, default_value_t = 1000)]
+    pub sample_records: usize,
+}
+
+/// columns of every record, fixed by the VCF format or computed from it: name, Type, Number and description
+const SITE_COLUMNS: [(&str, &str, &str, &str); 16] = [
+    ("chromosome", "String", "1", "CHROM"),
+    ("position", "Integer", "1", "POS"),
+    ("id", "String", ".", "ID"),
+    ("reference", "String", "1", "REF"),
+    ("alternative", "String", "A", "ALT"),
+    ("qual", "Float", "1", "QUAL"),
+    ("filter", "String", ".", "FILTER"),
+    ("variant_type", "String", "1", "SNV, MNV, insertion, deletion, complex, symbolic, breakend, spanning_deletion or no_alt"),
+    ("length_change", "Integer", "1", "length of ALT minus length of REF, or SVLEN"),
+    ("is_transition", "Flag", "1", "whether a SNV is a transition"),
+    ("end", "Integer", "1", "last position of the variant, from END for SVs"),
+    ("sv_type", "String", "1", "SVTYPE"),
+    ("mate_chromosome", "String", "1", "chromosome of the mate of a breakend"),
+    ("mate_position", "Integer", "1", "position of the mate of a breakend"),
+    ("cipos", "Integer", "2", "CIPOS"),
+    ("ciend", "Integer", "2", "CIEND"),
+];
+
+/// header of the tsv listing
+pub const HEADER: [&str; 6] = ["name", "source", "type", "number", "description", "inferred_type"];
+
+/// A column as listed by the `schema` subcommand
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnInfo {
+    /// name in the output
+    pub name: String,
+    /// site, info, subfield (of a nested field such as CSQ), sample, annotation or derived
+    pub source: &'static str,
+    /// Type in the VCF header
+    pub value_type: Option<String>,
+    /// Number in the VCF header
+    pub number: Option<String>,
+    pub description: String,
+    /// type of the values seen, for columns without a Type in the header
+    pub inferred_type: Option<&'static str>,
+}
+
+fn value_type(x: &ValueType) -> String {
+    match x {
+        ValueType::Integer => "Integer",
+        ValueType::Float => "Float",
+        ValueType::Flag => "Flag",
+        ValueType::Character => "Character",
+        ValueType::String => "String",
+    }.to_string()
+}
+
+fn number(x: &Number) -> String {
+    match x {
+        Number::Number(n) => n.to_string(),
+        Number::Allele => "A".to_string(),
+        Number::Reference => "R".to_string(),
+        Number::Genotype => "G".to_string(),
+        Number::Unknown => ".".to_string(),
+    }
+}
+
+impl ColumnInfo {
+    /// describe `column` of an input, output as `name`. `derived` are the names of the derived columns
+    pub fn new(column: &str, name: &str, header: &VCFHeader, schema: &Schema, derived: &[String]) -> Self {
+        let mut info = ColumnInfo { name: name.to_string(), source: "annotation", value_type: None, number: None, description: String::new(), inferred_type: None };
+        if let Some((_, value_type, number, description)) = SITE_COLUMNS.iter().find(|(x, ..)| *x == column) {
+            info.source = "site";
+            info.value_type = Some(value_type.to_string());
+            info.number = Some(number.to_string());
+            info.description = description.to_string();
+        } else if let Some(field) = column.strip_prefix("info.").and_then(|x| header.info(x.as_bytes())) {
+            info.source = "info";
+            info.value_type = Some(value_type(field.value_type));
+            info.number = Some(number(field.number));
+            info.description = String::from_utf8_lossy(field.description).to_string();
+        } else if schema.id(column).is_some_and(|id| schema.is_subfield(id)) {
+            info.source = "subfield";
+            let field = column.trim_start_matches("info.").split('.').next().unwrap_or_default();
+            info.description = format!("subfield of {}", field);
+        } else if schema.id(column).is_some_and(|id| schema.is_sample(id)) {
+            info.source = "sample";
+            let format = header.format_list().map(|x| String::from_utf8_lossy(x).to_string()).collect::<Vec<String>>();
+            info.description = format!("FORMAT fields of the sample: {}", format.join(", "));
+        } else if derived.iter().any(|x| x == column) {
+            info.source = "derived";
+            info.description = "computed from other columns".to_string();
+        } else {
+            info.description = match column {
+                "inheritance" => "inheritance models the variant fits",
+                "comphet_id" => "compound heterozygote pair of the variant",
+                "source_file" => "input file of the record",
+                x if x.starts_with("cohort.") => "allele statistics computed from the genotypes",
+                _ => "",
+            }.to_string();
+        }
+        info
+    }
+
+    pub fn to_tsv(&self) -> Vec<String> {
+        vec![
+            self.name.to_string(),
+            self.source.to_string(),
+            self.value_type.clone().unwrap_or_default(),
+            self.number.clone().unwrap_or_default(),
+            self.description.to_string(),
+            self.inferred_type.unwrap_or_default().to_string(),
+        ]
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "source": self.source,
+            "type": self.value_type,
+            "number": self.number,
+            "description": self.description,
+            "inferred_type": self.inferred_type,
+        })
+    }
+}
+
+/// Types of the values seen in some columns, widening Integer to Float and anything mixed to String
+#[derive(Clone, Debug)]
+pub struct InferredTypes {
+    columns: Vec<String>,
+    types: Vec<Option<&'static str>>,
+}
+
+impl InferredTypes {
+    pub fn new(columns: &[String]) -> Self {
+        InferredTypes { columns: columns.to_vec(), types: vec![None; columns.len()] }
+    }
+
+    pub fn add(&mut self, row: &Row, schema: &Schema) {
+        for (column, inferred) in self.columns.iter().zip(self.types.iter_mut()) {
+            if let Some(cell) = schema.get(row, column) {
+                add_cell(inferred, cell);
+            }
+        }
+    }
+
+    pub fn get(&self, ind: usize) -> Option<&'static str> {
+        self.types[ind]
+    }
+}
+
+fn add_cell(inferred: &mut Option<&'static str>, cell: &Cell) {
+    let seen = match cell {
+        // FORMAT fields of a sample have no type of their own
+        Cell::Null | Cell::Json(_) => return,
+        Cell::Str(x) if x.is_empty() => return,
+        // lists of an unexploded field have the type of their elements
+        Cell::List(x) => return x.iter().for_each(|x| add_cell(inferred, x)),
+        Cell::Bool(_) => "Flag",
+        Cell::Int(_) => "Integer",
+        Cell::Float(_) => "Float",
+        Cell::Str(_) => "String",
+    };
+    *inferred = match (*inferred, seen) {
+        (None, x) => Some(x),
+        (Some(x), y) if x == y => Some(x),
+        (Some("Integer"), "Float") | (Some("Float"), "Integer") => Some("Float"),
+        _ => Some("String"),
+    };
+}
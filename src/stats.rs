@@ -0,0 +1,221 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde_json::{json, Map, Value};
+use crate::row::{Cell, Row, Schema};
+use crate::utils;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct StatsArgs {
+    /// numeric columns to make histograms of, as column[:bin width], e.g. info.CADD_PHRED:5. Bin width defaults to 1
+    #[arg(long, value_delimiter = ',')]
+    pub histogram: Vec<String>,
+}
+
+impl StatsArgs {
+    /// columns read from the filtered rows, besides the nested fields, which are always decoded
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns = vec!["format.GT".to_string()];
+        columns.extend(self.histogram.iter().map(|x| x.rsplit_once(':').map_or(x.as_str(), |(column, _)| column).to_string()));
+        columns
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct SampleStats {
+    het: u64,
+    hom_alt: u64,
+    missing: u64,
+}
+
+/// QC summary of the records passing the filters.
+/// Partial summaries made on different threads are combined with `merge`.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// column and bin width of each histogram
+    histograms: Vec<(String, f64)>,
+    /// Consequence and IMPACT subfields of the nested fields, e.g. info.CSQ.Consequence
+    consequence_columns: Vec<String>,
+    impact_columns: Vec<String>,
+    records: u64,
+    chromosome: BTreeMap<String, u64>,
+    filter: BTreeMap<String, u64>,
+    variant_type: BTreeMap<String, u64>,
+    transitions: u64,
+    transversions: u64,
+    /// length change of insertions (positive) and deletions (negative)
+    indel_length: BTreeMap<i64, u64>,
+    /// records per consequence and impact, counted once however many rows of a record have them
+    consequence: BTreeMap<String, u64>,
+    impact: BTreeMap<String, u64>,
+    samples: BTreeMap<String, SampleStats>,
+    /// column -> bin index -> count
+    histogram_counts: BTreeMap<String, BTreeMap<i64, u64>>,
+}
+
+fn add_counts<K: Ord>(counts: &mut BTreeMap<K, u64>, other: BTreeMap<K, u64>) {
+    for (key, count) in other {
+        *counts.entry(key).or_default() += count;
+    }
+}
+
+fn text(cell: Option<&Cell>) -> String {
+    match cell {
+        Some(Cell::Str(s)) if !s.is_empty() => s.to_string(),
+        Some(Cell::Str(_)) | Some(Cell::Null) | None => ".".to_string(),
+        Some(x) => x.to_tsv(),
+    }
+}
+
+impl Stats {
+    /// `fields` are the nested fields parsed, such as info.CSQ, whose Consequence and IMPACT subfields are counted
+    pub fn new(args: &StatsArgs, fields: &[String]) -> Result<Self, String> {
+        let histograms = args.histogram.iter().map(|x| match x.rsplit_once(':') {
+            Some((column, width)) => match width.parse::<f64>() {
+                Ok(width) if width > 0.0 => Ok((column.to_string(), width)),
+                _ => Err(format!("invalid bin width in {}", x)),
+            },
+            None => Ok((x.to_string(), 1.0)),
+        }).collect::<Result<Vec<(String, f64)>, String>>()?;
+        Ok(Stats {
+            histograms,
+            consequence_columns: fields.iter().map(|x| format!("{}.Consequence", x)).collect(),
+            impact_columns: fields.iter().map(|x| format!("{}.IMPACT", x)).collect(),
+            records: 0,
+            chromosome: BTreeMap::new(),
+            filter: BTreeMap::new(),
+            variant_type: BTreeMap::new(),
+            transitions: 0,
+            transversions: 0,
+            indel_length: BTreeMap::new(),
+            consequence: BTreeMap::new(),
+            impact: BTreeMap::new(),
+            samples: BTreeMap::new(),
+            histogram_counts: BTreeMap::new(),
+        })
+    }
+
+    /// add a record, given its rows passing the filters, whose columns are looked up in the schema of its input.
+    /// Site fields are taken from the first row, consequences and impacts from all of them,
+    /// such as every transcript of an exploded record
+    pub fn add(&mut self, rows: &[&Row], schema: &Schema) {
+        let Some(site) = rows.first() else {
+            return;
+        };
+        self.records += 1;
+        *self.chromosome.entry(text(schema.get(site, "chromosome"))).or_default() += 1;
+        *self.filter.entry(text(schema.get(site, "filter"))).or_default() += 1;
+        let variant_type = text(schema.get(site, "variant_type"));
+        match schema.get(site, "is_transition") {
+            Some(Cell::Bool(true)) => self.transitions += 1,
+            Some(Cell::Bool(false)) => self.transversions += 1,
+            _ => (),
+        }
+        if variant_type == "insertion" || variant_type == "deletion" {
+            if let Some(Cell::Int(length)) = schema.get(site, "length_change") {
+                *self.indel_length.entry(*length).or_default() += 1;
+            }
+        }
+        *self.variant_type.entry(variant_type).or_default() += 1;
+        for (columns, counts) in [(&self.consequence_columns, &mut self.consequence), (&self.impact_columns, &mut self.impact)] {
+            let terms = rows.iter()
+                .flat_map(|row| columns.iter().filter_map(|x| schema.get(row, x)).flat_map(Cell::elements))
+                .filter_map(|x| x.as_str())
+                .flat_map(|x| x.split('&'))
+                .filter(|x| !x.is_empty())
+                .collect::<BTreeSet<&str>>();
+            for term in terms {
+                *counts.entry(term.to_string()).or_default() += 1;
+            }
+        }
+        for (sample, gt) in schema.sample_gt(site) {
+            let sample_stats = self.samples.entry(sample.to_string()).or_default();
+            match gt.and_then(utils::alt_allele_count) {
+                None => sample_stats.missing += 1,
+                Some((0, _)) => (),
+                Some((alt, ploidy)) if alt < ploidy => sample_stats.het += 1,
+                Some(_) => sample_stats.hom_alt += 1,
+            }
+        }
+        for (column, width) in &self.histograms {
+            let counts = self.histogram_counts.entry(column.to_string()).or_default();
+            for x in schema.get(site, column).map(Cell::elements).unwrap_or_default().iter().filter_map(Cell::as_f64) {
+                *counts.entry((x / width).floor() as i64).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn merge(mut self, other: Stats) -> Self {
+        self.records += other.records;
+        add_counts(&mut self.chromosome, other.chromosome);
+        add_counts(&mut self.filter, other.filter);
+        add_counts(&mut self.variant_type, other.variant_type);
+        self.transitions += other.transitions;
+        self.transversions += other.transversions;
+        add_counts(&mut self.indel_length, other.indel_length);
+        add_counts(&mut self.consequence, other.consequence);
+        add_counts(&mut self.impact, other.impact);
+        for (sample, other) in other.samples {
+            let sample_stats = self.samples.entry(sample).or_default();
+            sample_stats.het += other.het;
+            sample_stats.hom_alt += other.hom_alt;
+            sample_stats.missing += other.missing;
+        }
+        for (column, other) in other.histogram_counts {
+            add_counts(self.histogram_counts.entry(column).or_default(), other);
+        }
+        self
+    }
+
+    /// the report as one json object, with a section per statistic
+    pub fn to_json(&self) -> Value {
+        let ratio = |x: u64, y: u64| if y == 0 { Value::Null } else { Value::from(x as f64 / y as f64) };
+        let samples = self.samples.iter().map(|(sample, x)| (sample.to_string(), json!({
+            "het": x.het,
+            "hom_alt": x.hom_alt,
+            "het_hom_ratio": ratio(x.het, x.hom_alt),
+            "missing": x.missing,
+            "missingness": ratio(x.missing, self.records),
+        }))).collect::<Map<String, Value>>();
+        let histograms = self.histograms.iter().map(|(column, width)| {
+            let bins = self.histogram_counts.get(column).map(|counts| counts.iter()
+                .map(|(bin, count)| ((*bin as f64 * width).to_string(), Value::from(*count)))
+                .collect::<Map<String, Value>>()).unwrap_or_default();
+            (column.to_string(), Value::Object(bins))
+        }).collect::<Map<String, Value>>();
+        json!({
+            "records": self.records,
+            "chromosome": self.chromosome,
+            "filter": self.filter,
+            "variant_type": self.variant_type,
+            "ti_tv": {"transitions": self.transitions, "transversions": self.transversions, "ratio": ratio(self.transitions, self.transversions)},
+            "indel_length": self.indel_length.iter().map(|(k, v)| (k.to_string(), Value::from(*v))).collect::<Map<String, Value>>(),
+            "consequence": self.consequence,
+            "impact": self.impact,
+            "samples": samples,
+            "histograms": histograms,
+        })
+    }
+
+    /// the report as section, key, value rows, nested keys joined by `.`
+    pub fn to_rows(&self) -> Vec<[String; 3]> {
+        let mut rows = Vec::new();
+        if let Value::Object(sections) = self.to_json() {
+            for (section, val) in sections {
+                flatten(&section, "", &val, &mut rows);
+            }
+        }
+        rows
+    }
+}
+
+fn flatten(section: &str, prefix: &str, val: &Value, rows: &mut Vec<[String; 3]>) {
+    match val {
+        Value::Object(map) => {
+            for (key, val) in map {
+                let key = if prefix.is_empty() { key.to_string() } else { format!("{}.{}", prefix, key) };
+                flatten(section, &key, val, rows);
+            }
+        },
+        Value::Null => rows.push([section.to_string(), prefix.to_string(), String::new()]),
+        x => rows.push([section.to_string(), prefix.to_string(), x.to_string()]),
+    }
+}
@@ -2,7 +2,10 @@ use std::error::Error;
 use std::collections::HashMap;
 use calm_io::stdoutln;
 use serde_json::{Map, Value, Number};
-use std::rc::Rc;
+use crate::JoinType;
+use crate::derive::DerivedColumn;
+use crate::error::VcfParserError;
+use crate::filter_library::FilterLibrary;
 
 pub fn print_line_to_stdout(line: &str) -> Result<(), Box<dyn Error>> {
     // output line to stdout.
@@ -24,226 +27,142 @@ pub fn parse_csq_header(header: &str) -> Vec<String> {
         .collect::<Vec<String>>()
 }
 
-pub fn filter_record(record: &Map<String,Value>, filters: &Value) -> bool {
-    // filter the variant based on the filters. Filter is like:
-    /*
-    {  
-        "AND":[
-            {
-                "AND":[
-                    {"name":"gnomAD_exome_V4.0_AF","op":"le","value":0.05},
-                    {"name":"gnomAD_genome_V4.0_AF","op":"le","value":0.05}
-                ]
-            },
-            {
-                "OR":[
-                    {"name":"CADD_PHRED","op":"ge","value":20},
-                    {"name":"Lof","op":"ne","value":null}
-                ]
-            }
-        ]
+pub fn parse_nested_format(description: &str) -> Option<Vec<String>> {
+    // find a `Format: x|y|z` pattern in an INFO description, such as the ones written by VEP.
+    // returns None if the description does not describe a `|` separated field.
+    let start = description.find("Format:")? + "Format:".len();
+    let format = description[start..].trim();
+    let format = format.split_whitespace().next()?;
+    if !format.contains('|') {
+        return None;
     }
-    This function is recursive, so it can handle nested AND/OR
+    Some(format.split('|').map(|x| x.trim().to_string()).collect())
+}
+
+pub fn infer_join_key(subfields: &[String]) -> Option<String> {
+    // pick the subfield that most likely holds a transcript id, e.g. Feature for VEP or pangolin_transcript
+    subfields.iter()
+        .find(|x| x.as_str() == "Feature")
+        .or_else(|| subfields.iter().find(|x| x.to_lowercase().contains("transcript")))
+        .map(|x| x.to_string())
+}
+
+pub fn load_filter_file(path: &str) -> Result<(Value, Vec<DerivedColumn>), Box<dyn Error>> {
+    // a filter yaml is either a filter tree, or has a `filter` section with the tree, a `derive` section
+    // of computed columns, such as `pangolin_abs: abs(info.Pangolin.pangolin_max_score)`, and named `filters`
+    let mut library = FilterLibrary::default();
+    let filter = library.load(path)?;
+    Ok((library.resolve(&filter)?, library.derived))
+}
 
-    One can potentially convert this into logic expression and parse through coolrule, but it would be many times slower!
-    */
+pub fn load_sample_list(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    // one sample per line
+    let samples = std::fs::read_to_string(path)?;
+    Ok(samples.lines().map(|x| x.trim()).filter(|x| !x.is_empty() && !x.starts_with('#')).map(|x| x.to_string()).collect())
+}
+
+pub fn load_input_list(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    // one VCF per line, relative to the working directory
+    let inputs = std::fs::read_to_string(path)?;
+    Ok(inputs.lines().map(|x| x.trim()).filter(|x| !x.is_empty() && !x.starts_with('#')).map(|x| x.to_string()).collect())
+}
+
+pub fn load_sample_names(path: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    // tab separated VCF sample name and new name, one sample per line
+    let mut names = HashMap::new();
+    for line in std::fs::read_to_string(path)?.lines().filter(|x| !x.trim().is_empty() && !x.starts_with('#')) {
+        match line.split_once('\t') {
+            Some((old, new)) => names.insert(old.trim().to_string(), new.trim().to_string()),
+            None => return Err(format!("sample rename line should have two tab separated columns: {}", line).into()),
+        };
+    }
+    Ok(names)
+}
+
+pub fn filter_columns(filters: &Value) -> Vec<String> {
+    // columns used by the conditions of a filter tree
     match filters {
-        Value::Object(map) => {
-            for (k, v) in map {
-                if k.eq_ignore_ascii_case("AND") {
-                    match v {
-                        Value::Array(vv) => {
-                            if vv.into_iter().all(|x| filter_record(record, x)) {
-                                return true;
-                            } else {
-                                return false;
-                            }
-                        }
-                        _ => panic!("AND should be a list of filters"),
-                    }
-                } else if k.eq_ignore_ascii_case("OR") {
-                    match v {
-                        Value::Array(vv) => {
-                            if vv.into_iter().any(|x| filter_record(record, x)) {
-                                return true;
-                            } else {
-                                return false;
-                            }
-                        }
-                        _ => panic!("OR should be a list of filters"),
-                    }
-                } else {
-                    let name = map["name"].as_str().unwrap();
-                    let op = map["op"].as_str().unwrap();
-                    let value = &map["value"];
-                    let val = record.get(name).unwrap_or(&Value::Null);
-                    match op {
-                        "eq" | "=" | "==" => {
-                            if *val == *value {
-                                return true;
-                            } 
-                            return false;    
-                        }
-                        "ne" | "!=" | "≠" => {
-                            if *val != *value {
-                                return true;
-                            }
-                            return false;
-                        }
-                        "gt" | ">" => {
-                            if val.is_null() {
-                                return false;
-                            }
-                            if val.as_f64().unwrap() > value.as_f64().unwrap() {
-                                return true;
-                            }
-                            return false;
-                        }
-                        "ge" | ">=" | "≥" => {
-                            if val.is_null() {
-                                return false;
-                            }
-                            if val.as_f64().unwrap() >= value.as_f64().unwrap() {
-                                return true;
-                            } 
-                            return false;
-                        }
-                        "lt" | "<" => {
-                            if val.is_null() {
-                                return false;
-                            }
-                            if val.as_f64().unwrap() < value.as_f64().unwrap() {
-                                return true;
-                            }
-                            return false;
-                        }
-                        "le" | "<=" | "≤" => {
-                            if val.is_null() {
-                                return false;
-                            }
-                            if val.as_f64().unwrap() <= value.as_f64().unwrap() {
-                                return true;
-                            } 
-                            return false;
-                        }
-                        "in" | "∈" => {
-                            match value {
-                                serde_json::Value::Array(arr) => {
-                                    return arr.contains(val)
-                                }
-                                _ => {
-                                    if val == value {
-                                        return true;
-                                    } else {
-                                        return false;
-                                    }
-                                }
-                            }
-                        }
-                        _ => panic!("Unknown operator"),
-                    }
-                }
-            };
-            false
+        Value::Object(map) => match map.get("name") {
+            Some(Value::String(name)) => vec![name.to_string()],
+            _ => map.values().flat_map(filter_columns).collect(),
         },
-        serde_json::Value::Null => true,
-        _ => false
+        Value::Array(arr) => arr.iter().flat_map(filter_columns).collect(),
+        _ => vec![],
     }
 }
 
-pub fn outer_join(mut tables: Vec<Vec<Map<String, Value>>>, keys: &Vec<String>) -> Result<Vec<Map<String, Value>>, Box<dyn Error>> {
-    if tables.len() == 0 {
-        return Ok(vec![]);
-    }
-    if tables.len() != keys.len() {
-        return Err("Number of tables should be equal to the number of keys".into());
-    }
-    // Create a map to hold the result of the outer join
-    // right_table would be the joint table
-    let mut keys = keys.clone();
-    let mut right_table: Vec<Map<String, Value>> = tables.pop().unwrap();
-    let right_key = keys.pop().unwrap();
-    while let Some(mut left_table) = tables.pop() {
-        // get all keys from the left table
-        let left_firstrow = left_table[0].to_owned();
-        let left_keys = Rc::new(left_firstrow.keys().collect::<Vec<&String>>());
-        let right_firstrow = right_table[0].to_owned();
-        let right_keys = Rc::new(right_firstrow.keys().collect::<Vec<&String>>());
-        let left_key = keys.pop().unwrap();
-        // Iterate over each entry in the right table, and remove  entries in the left table that are joined
-        right_table.iter_mut().for_each(|right_entry| {
-            let right_value = match right_entry.get(&right_key).unwrap(){
-                Value::Null => "__MISSING__",
-                x => x.as_str().unwrap(),
-            }.split(".").collect::<Vec<&str>>();
-            // fight the matching left entry, and delete the left entry from the left table
-            if let Some(left_index) = left_table.iter().position(|left_entry| {
-                let left_value = match left_entry.get(&left_key).unwrap(){
-                    Value::Null => "__MISSING__",
-                    x => x.as_str().unwrap(),
-                }.split(".").collect::<Vec<&str>>();
-                left_value.first().unwrap() == right_value.first().unwrap()
-            }) {
-                let left_entry = left_table.remove(left_index);
-                for (k, v) in left_entry {
-                    // skip if the key is already in the entry, and it is not Null
-                    if right_entry.get(&k).unwrap_or(&Value::Null) == &Value::Null {
-                        right_entry.insert(k.to_string(), v);
-                    }
-                }
-            } else {
-                // fill in null to all the keys in the left table that are not in the right table
-                for &k in left_keys.iter() {
-                    if right_entry.get(k).unwrap_or(&Value::Null) == &Value::Null {
-                        right_entry.insert(k.to_string(), Value::Null);
-                    }
-                }
-            }
-        });
-        // add the left table (whatever left) to the right table. fill missing right keys with null
-        for left_entry in left_table {
-            let mut new_entry = left_entry;
-            for &k in right_keys.iter() {
-                if new_entry.get(k).unwrap_or(&Value::Null) == &Value::Null {
-                    new_entry.insert(k.to_string(), Value::Null);
-                }
-            }
-            right_table.push(new_entry);
+/// A genomic interval, 1-based and inclusive, such as chr1:100-200
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    pub chromosome: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Region {
+    pub fn parse(region: &str) -> Result<Self, Box<dyn Error>> {
+        // chr1:100-200, chr1:100 or chr1
+        let region = region.trim();
+        let (chromosome, interval) = match region.rsplit_once(':') {
+            Some((chromosome, interval)) => (chromosome, Some(interval)),
+            None => (region, None),
+        };
+        let (start, end) = match interval {
+            None => (1, u64::MAX),
+            Some(interval) => match interval.split_once('-') {
+                Some((start, end)) => (start.parse::<u64>()?, end.parse::<u64>()?),
+                None => {
+                    let position = interval.parse::<u64>()?;
+                    (position, position)
+                },
+            },
+        };
+        if chromosome.is_empty() || start > end {
+            return Err(format!("Invalid region {}", region).into());
         }
+        Ok(Region { chromosome: chromosome.to_string(), start, end })
+    }
+
+    pub fn overlaps(&self, chromosome: &str, start: u64, end: u64) -> bool {
+        self.chromosome == chromosome && start <= self.end && end >= self.start
     }
-    Ok(right_table)
 }
 
-pub fn explode_data(data:Value, key: &str, drops: &Vec<String>) -> Vec<Map<String, Value>> {
-    // explode on key, but drop the columns in `drops`.
-    // dropping columns is desirable if one row is going to be exploded on multiple keys, and it will result in duplicated columns
-    let mut result: Vec<Map<String, Value>> = vec![];
-    match data.get(key).unwrap_or(&Value::Null) {
-        Value::Array(arr) => {
-            for a in arr {
-                let mut new_record = data.as_object().unwrap().to_owned();
-                for drop in drops {
-                    new_record.remove(drop);
-                }
-                match a {
-                    Value::Object(map) => {
-                        for (k, v) in map {
-                            let k = format!("{}.{}", key, k);
-                            new_record.insert(k, v.to_owned());
-                        }
-                        result.push(new_record);
-                    },
-                    _ => panic!("Array should contain objects"),
+/// the regions of an `overlaps` condition, a region like chr1:100-200 or a list of them
+pub fn parse_regions(regions: &Value) -> Result<Vec<Region>, VcfParserError> {
+    let regions = match regions {
+        Value::Array(arr) => arr.iter().collect::<Vec<&Value>>(),
+        x => vec![x],
+    };
+    regions.iter().map(|x| match x.as_str().map(Region::parse) {
+        Some(Ok(region)) => Ok(region),
+        _ => Err(VcfParserError::InvalidFilter(format!("overlaps should be given regions like chr1:100-200, not {}", x))),
+    }).collect()
+}
+
+pub fn set_array_match(filters: &mut Value, array_match: &str) {
+    // set how filter conditions match unexploded arrays ("any" or "all"), unless a condition sets it already
+    match filters {
+        Value::Object(map) => {
+            if map.contains_key("name") {
+                map.entry("match").or_insert_with(|| Value::String(array_match.to_string()));
+            } else {
+                for v in map.values_mut() {
+                    set_array_match(v, array_match);
                 }
             }
         },
-        _ => {
-            println!("{:?}", data);
-            println!("{:?}", key);
-            panic!("Data should be an array")
-        },
+        Value::Array(arr) => arr.iter_mut().for_each(|x| set_array_match(x, array_match)),
+        _ => (),
     }
-    result
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct JoinOptions {
+    /// how to treat rows that have no match in the other table
+    pub join_type: JoinType,
+    /// compare ids without their version suffix, e.g. ENST00000392370.5 matches ENST00000392370
+    pub ignore_version: bool,
 }
 
 pub fn get_row(data:&Map<String, Value>, header:&Vec<String>) -> Vec<String> {
@@ -251,7 +170,7 @@ pub fn get_row(data:&Map<String, Value>, header:&Vec<String>) -> Vec<String> {
     // if genotype exists, extract sample genotype
     // for tsv output
     header.iter().map(|x| {
-        match data.get(x).unwrap_or(data.get("genotype").unwrap().get(x).unwrap_or(&Value::Null)) {
+        match data.get(x).or_else(|| data.get("genotype").and_then(|g| g.get(x))).unwrap_or(&Value::Null) {
             Value::Null => "".to_string(),
             Value::Number(n) => n.to_string(),
             Value::String(s) => s.as_str().to_string(),
@@ -260,9 +179,10 @@ pub fn get_row(data:&Map<String, Value>, header:&Vec<String>) -> Vec<String> {
     }).collect::<Vec<String>>()
 }
 
-pub fn get_output_header(info_header: &Vec<String>, csq_header: &HashMap<String, Vec<String>>, samples: &[Vec<u8>], user_columns: &Option<Vec<String>>) -> Vec<String> {
+pub fn get_output_header(info_header: &Vec<String>, csq_header: &HashMap<String, Vec<String>>, samples: &[Vec<u8>], extra_columns: &[String], user_columns: &Option<Vec<String>>) -> Result<Vec<String>, VcfParserError> {
     // get the header for csv output
     // essential columns are in the front. All info columns are in the back, sorted alphabetically.
+    // extra columns, such as derived ones, come after the info columns and before the samples.
     let mut header: Vec<String> = Vec::new();
     for h in info_header {
         if csq_header.contains_key(h) {
@@ -279,7 +199,7 @@ pub fn get_output_header(info_header: &Vec<String>, csq_header: &HashMap<String,
     // turn samples into strings
     let samples = samples.iter().map(|x| std::str::from_utf8(x).unwrap().to_string()).collect::<Vec<String>>();
 
-    // add chromosome, position, id, ref, alt, qual, filter
+    // add chromosome, position, id, ref, alt, qual, filter, and the computed variant class and SV columns
     let header = vec![
         "chromosome".to_string(),
         "position".to_string(),
@@ -288,24 +208,238 @@ pub fn get_output_header(info_header: &Vec<String>, csq_header: &HashMap<String,
         "alternative".to_string(),
         "qual".to_string(),
         "filter".to_string(),
+        "variant_type".to_string(),
+        "length_change".to_string(),
+        "is_transition".to_string(),
+        "end".to_string(),
+        "sv_type".to_string(),
+        "mate_chromosome".to_string(),
+        "mate_position".to_string(),
+        "cipos".to_string(),
+        "ciend".to_string(),
     ].iter()
     .chain(header.iter())
+    .chain(extra_columns.iter())
     .chain(samples.iter())
     .map(|x| x.to_string()).collect::<Vec<String>>();
 
     // if user_columns is None, then all columns are selected
-    // validate if user_columns are a subset of header
-    match user_columns {
-        Some(user_columns) => {
-            for user_column in user_columns {
-                if !header.contains(&user_column) {
-                    panic!("Column {} is not in the header", user_column);
-                }
+    select_columns(&header, user_columns.as_deref(), &[], true)
+}
+
+pub fn select_columns(header: &[String], patterns: Option<&[String]>, exclude: &[String], strict: bool) -> Result<Vec<String>, VcfParserError> {
+    // columns of the header in the order of the patterns, all of them if None. A glob such as info.CSQ.* is expanded
+    // in header order, skipping columns already selected. Columns matching an `exclude` pattern are left out.
+    // If strict, a pattern matching no column is an error, with the closest column names if any.
+    let matches = |pattern: &String| -> Result<Vec<String>, VcfParserError> {
+        let matched = header.iter().filter(|x| glob_match(pattern, x)).cloned().collect::<Vec<String>>();
+        if matched.is_empty() && strict {
+            return Err(unknown_column(pattern, header));
+        }
+        Ok(matched)
+    };
+    let mut columns: Vec<String> = Vec::new();
+    match patterns {
+        Some(patterns) => for pattern in patterns {
+            let matched = matches(pattern)?;
+            if is_glob(pattern) {
+                columns.extend(matched.into_iter().filter(|x| !columns.contains(x)).collect::<Vec<String>>());
+            } else {
+                columns.extend(matched);
             }
-            user_columns.clone()
         },
-        None => header,
+        None => columns = header.to_vec(),
+    }
+    for pattern in exclude {
+        matches(pattern)?;
+        columns.retain(|x| !glob_match(pattern, x));
+    }
+    Ok(columns)
+}
+
+fn unknown_column(name: &str, header: &[String]) -> VcfParserError {
+    let mut message = format!("Column {} is not in the header", name);
+    let suggestions = close_matches(name, header);
+    if !suggestions.is_empty() {
+        message.push_str(&format!(", did you mean {}?", suggestions.join(", ")));
+    }
+    VcfParserError::InvalidArgument(message)
+}
+
+pub fn output_names(header: &[String], rename: &[(String, String)], strip_prefix: bool, strict: bool) -> Result<HashMap<String, String>, VcfParserError> {
+    // column -> name to output it as, for the renamed columns.
+    // With strip_prefix, an INFO column is named by its shortest suffix no other column has, such as
+    // SYMBOL for info.CSQ.SYMBOL, or CSQ.Gene if info.Pangolin.Gene exists. Explicit renames take precedence.
+    // Unless strict, renamed columns not in the header are skipped
+    let mut names = HashMap::new();
+    if strip_prefix {
+        let suffixes = |column: &str| -> Vec<String> {
+            match column.strip_prefix("info.") {
+                Some(rest) => rest.rmatch_indices('.').map(|(ind, _)| rest[ind + 1..].to_string()).chain([rest.to_string()]).collect(),
+                None => vec![],
+            }
+        };
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for column in header {
+            for suffix in suffixes(column) {
+                *counts.entry(suffix).or_default() += 1;
+            }
+        }
+        for column in header {
+            if let Some(suffix) = suffixes(column).into_iter().find(|x| counts[x] == 1 && !header.contains(x)) {
+                names.insert(column.to_string(), suffix);
+            }
+        }
+    }
+    for (old, new) in rename {
+        if !header.contains(old) {
+            if strict {
+                return Err(unknown_column(old, header));
+            }
+            continue;
+        }
+        names.insert(old.to_string(), new.to_string());
+    }
+    // output names have to be unique
+    let mut seen: HashMap<&String, &String> = HashMap::new();
+    for column in header {
+        let name = names.get(column).unwrap_or(column);
+        if let Some(other) = seen.insert(name, column) {
+            return Err(VcfParserError::InvalidArgument(format!("Columns {} and {} would both be output as {}", other, column, name)));
+        }
+    }
+    Ok(names)
+}
+
+pub fn load_rename_file(path: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    // a yaml mapping of column names to output names, such as `info.CSQ.SYMBOL: gene`
+    let yaml: serde_yaml::Mapping = serde_yaml::from_reader(std::fs::File::open(path)?)?;
+    let mut names = Vec::new();
+    for (old, new) in yaml {
+        match (old.as_str(), new.as_str()) {
+            (Some(old), Some(new)) => names.push((old.to_string(), new.to_string())),
+            _ => return Err(format!("{} should map column names to new names", path).into()),
+        }
+    }
+    Ok(names)
+}
+
+pub fn rename_filter_columns(filters: &mut Value, columns: &HashMap<String, String>) {
+    // refer to the columns of filter conditions by their names in the header, e.g. info.CSQ.SYMBOL for gene
+    match filters {
+        Value::Object(map) => {
+            match map.get_mut("name") {
+                Some(Value::String(name)) => if let Some(column) = columns.get(name.as_str()) {
+                    *name = column.to_string();
+                },
+                _ => map.values_mut().for_each(|x| rename_filter_columns(x, columns)),
+            }
+        },
+        Value::Array(arr) => arr.iter_mut().for_each(|x| rename_filter_columns(x, columns)),
+        _ => (),
+    }
+}
+
+pub fn rename_keys(record: Map<String, Value>, names: &HashMap<String, String>) -> Map<String, Value> {
+    // top level keys of a json row under their output names
+    record.into_iter().map(|(key, value)| match names.get(&key) {
+        Some(name) => (name.to_string(), value),
+        None => (key, value),
+    }).collect()
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    // `*` matches any characters, dots included, and `?` one character
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    // position in the pattern after the last `*`, and in the name where that `*` match ends
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            },
+            Some(x) if *x == b'?' || *x == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                // let the last `*` match one more character
+                Some((after, end)) => {
+                    star = Some((after, end + 1));
+                    p = after;
+                    n = end + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+fn close_matches(name: &str, header: &[String]) -> Vec<String> {
+    // up to 3 columns a few edits away, or named the same without their prefix, e.g. Consequence for info.CSQ.Consequence
+    let name = name.to_lowercase();
+    let mut matches = header.iter()
+        .map(|x| (x, x.to_lowercase()))
+        .filter_map(|(x, lower)| {
+            let distance = edit_distance(&name, &lower);
+            let suffix = lower.rsplit('.').next() == Some(name.as_str());
+            (suffix || distance <= (name.len() / 4).max(1)).then_some((if suffix { 0 } else { distance }, x.to_string()))
+        })
+        .collect::<Vec<(usize, String)>>();
+    matches.sort();
+    matches.into_iter().take(3).map(|(_, x)| x).collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    // Levenshtein distance, one row at a time
+    let b = b.chars().collect::<Vec<char>>();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, x) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = diagonal + (x != *y) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+pub fn union_headers(headers: &[&Vec<String>]) -> Vec<String> {
+    // columns of every header, in the order of the first one.
+    // a column missing from it goes right after the column before it in its own header, so e.g. INFO columns stay together
+    let mut union: Vec<String> = Vec::new();
+    for header in headers {
+        let mut position = 0;
+        for column in header.iter() {
+            position = match union.iter().position(|x| x == column) {
+                Some(ind) => ind + 1,
+                None => {
+                    union.insert(position, column.to_string());
+                    position + 1
+                },
+            };
+        }
+    }
+    union
+}
+
+pub fn alt_allele_count(gt: &str) -> Option<(usize, usize)> {
+    // number of alt alleles and ploidy of a GT such as 0/1, 1|1 or 1 (haploid).
+    // None if any allele is missing, e.g. ./. or 0/.
+    let alleles = gt.split(['/', '|']).collect::<Vec<&str>>();
+    if alleles.iter().any(|x| *x == "." || x.is_empty()) {
+        return None;
     }
+    Some((alleles.iter().filter(|x| **x != "0").count(), alleles.len()))
 }
 
 pub fn try_parse_number(input: &str) -> Value {
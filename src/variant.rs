@@ -1,12 +1,19 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Number, Value};
 use vcf::VCFRecord;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str;
 use crate::utils;
 
 serde_with::with_prefix!(prefix_info "info.");
 
+/// INFO and FORMAT fields to decode, when only some are needed. The others are left out of the variant
+#[derive(Debug, Clone, Default)]
+pub struct Projection {
+    pub info: HashSet<String>,
+    pub format: HashSet<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Variant {
     pub chromosome: String,
@@ -16,6 +23,24 @@ pub struct Variant {
     pub alternative: String,
     pub qual: Option<f64>,
     pub filter: String,
+    /// SNV, MNV, insertion, deletion, complex, symbolic (e.g. <DEL>) or breakend
+    pub variant_type: String,
+    /// length of alt minus length of ref. For symbolic alleles, taken from INFO/SVLEN if present
+    pub length_change: Option<i64>,
+    /// A<->G or C<->T. Only set for SNVs
+    pub is_transition: Option<bool>,
+    /// INFO/END if present, then position + INFO/SVLEN for symbolic alleles, otherwise the last reference base
+    pub end: u64,
+    /// INFO/SVTYPE, or inferred from a symbolic or breakend alt, e.g. DEL for <DEL>
+    pub sv_type: Option<String>,
+    /// chromosome of the mate of a breakend, e.g. chr2 for N[chr2:123[
+    pub mate_chromosome: Option<String>,
+    /// position of the mate of a breakend
+    pub mate_position: Option<u64>,
+    /// confidence interval around position, from INFO/CIPOS
+    pub cipos: Option<Vec<i64>>,
+    /// confidence interval around end, from INFO/CIEND
+    pub ciend: Option<Vec<i64>>,
     #[serde(flatten, with="prefix_info")]
     pub info: Map<String, Value>,
     pub genotype: Map<String, Value>,
@@ -27,11 +52,25 @@ impl Variant {
         samples: &[Vec<u8>],
         csq_headers: &HashMap<String, Vec<String>>,
     ) -> Self {
+        Variant::with_projection(vcf_record, samples, csq_headers, None)
+    }
+
+    /// like `new`, but only decode the INFO and FORMAT fields in the projection, if given
+    pub fn with_projection(
+        vcf_record: &VCFRecord,
+        samples: &[Vec<u8>],
+        csq_headers: &HashMap<String, Vec<String>>,
+        projection: Option<&Projection>,
+    ) -> Self {
+        let wanted = |fields: fn(&Projection) -> &HashSet<String>, key: &str| projection.is_none_or(|x| fields(x).contains(key));
         // parse genotype
         let mut genotype = Map::new();
         for sample in samples {
             let mut sample_genotype = Map::new();
             for key in &vcf_record.format {
+                if !wanted(|x| &x.format, str::from_utf8(key).unwrap()) {
+                    continue;
+                }
                 let genotype_raw = vcf_record.genotype(&sample, &key);
                 let f_k = str::from_utf8(key).unwrap();
                 let val = str::from_utf8(&genotype_raw.unwrap()[0]).unwrap();
@@ -61,6 +100,9 @@ impl Variant {
         for id in vcf_record.header().info_list() {
             let field = vcf_record.header().info(id).unwrap();
             let field_str = str::from_utf8(&field.id).unwrap();
+            if !wanted(|x| &x.info, field_str) {
+                continue;
+            }
             let val = match vcf_record.info(id) {
                 Some(dat) => {
                     if *field.value_type == vcf::ValueType::Flag {
@@ -161,6 +203,35 @@ impl Variant {
             };
             info.insert(str::from_utf8(&field.id).unwrap().to_string(), val);
         }
+        // classify on the first alt, as input is assumed to be normalised
+        let reference = str::from_utf8(&vcf_record.reference).unwrap();
+        let first_alt = vcf_record.alternative.first().map(|x| str::from_utf8(x).unwrap()).unwrap_or(".");
+        let variant_type = classify(reference, first_alt);
+        let length_change = match variant_type {
+            "symbolic" | "breakend" => info_integer(vcf_record, b"SVLEN"),
+            "spanning_deletion" | "no_alt" => None,
+            _ => Some(first_alt.len() as i64 - reference.len() as i64),
+        };
+        let is_transition = match variant_type {
+            "SNV" => Some(matches!(
+                (reference.to_ascii_uppercase().as_str(), first_alt.to_ascii_uppercase().as_str()),
+                ("A", "G") | ("G", "A") | ("C", "T") | ("T", "C")
+            )),
+            _ => None,
+        };
+        let sv_type = info_string(vcf_record, b"SVTYPE").or_else(|| sv_type_from_alt(first_alt));
+        // a negative END is ignored, so the end comes from REF
+        let end = match (info_integer(vcf_record, b"END").and_then(|x| u64::try_from(x).ok()), length_change) {
+            (Some(end), _) => end,
+            // SVLEN of an insertion is the inserted length, so it ends where it starts
+            (None, Some(_)) if sv_type.as_deref() == Some("INS") => vcf_record.position,
+            (None, Some(svlen)) if variant_type == "symbolic" => vcf_record.position + svlen.unsigned_abs(),
+            _ => vcf_record.position + (reference.len() as u64).max(1) - 1,
+        };
+        let (mate_chromosome, mate_position) = match variant_type {
+            "breakend" => parse_mate(first_alt).unzip(),
+            _ => (None, None),
+        };
         Variant {
             chromosome: str::from_utf8(&vcf_record.chromosome).unwrap().to_string(),
             position: vcf_record.position,
@@ -185,9 +256,100 @@ impl Variant {
                 .join(","),
             reference: str::from_utf8(&vcf_record.reference).unwrap().to_string(),
             qual: vcf_record.qual,
+            variant_type: variant_type.to_string(),
+            length_change,
+            is_transition,
+            end,
+            sv_type,
+            mate_chromosome,
+            mate_position,
+            cipos: info_integers(vcf_record, b"CIPOS"),
+            ciend: info_integers(vcf_record, b"CIEND"),
             info,
             genotype: genotype,
         }
     }
 }
 
+impl Variant {
+    /// rename samples in the genotypes, e.g. to their output names from `--rename-samples`
+    pub fn rename_samples(&mut self, names: &HashMap<String, String>) {
+        if names.is_empty() {
+            return;
+        }
+        self.genotype = std::mem::take(&mut self.genotype)
+            .into_iter()
+            .map(|(sample, val)| (names.get(&sample).cloned().unwrap_or(sample), val))
+            .collect();
+    }
+}
+
+fn info_integer(vcf_record: &VCFRecord, key: &[u8]) -> Option<i64> {
+    // first value of an integer INFO field, if present
+    info_integers(vcf_record, key).and_then(|x| x.first().copied())
+}
+
+fn info_integers(vcf_record: &VCFRecord, key: &[u8]) -> Option<Vec<i64>> {
+    // all values of an integer INFO field, if present and valid
+    vcf_record.info(key)?
+        .iter()
+        .map(|x| str::from_utf8(x).ok().and_then(|x| x.parse::<i64>().ok()))
+        .collect::<Option<Vec<i64>>>()
+        .filter(|x| !x.is_empty())
+}
+
+fn info_string(vcf_record: &VCFRecord, key: &[u8]) -> Option<String> {
+    vcf_record.info(key)
+        .and_then(|x| x.first())
+        .and_then(|x| str::from_utf8(x).ok())
+        .filter(|x| !x.is_empty() && *x != ".")
+        .map(|x| x.to_string())
+}
+
+pub fn sv_type_from_alt(alternative: &str) -> Option<String> {
+    // <DEL> -> DEL, <DUP:TANDEM> -> DUP, breakends -> BND
+    if let Some(symbolic) = alternative.strip_prefix('<').and_then(|x| x.strip_suffix('>')) {
+        return symbolic.split(':').next().map(|x| x.to_string());
+    }
+    match classify("N", alternative) {
+        "breakend" => Some("BND".to_string()),
+        _ => None,
+    }
+}
+
+pub fn parse_mate(alternative: &str) -> Option<(String, u64)> {
+    // mate location of a breakend such as N[chr2:123[, ]chr2:123]N, or N]<ctg1>:5]
+    let start = alternative.find(['[', ']'])?;
+    let bracket = alternative[start..].chars().next()?;
+    let location = &alternative[start + 1..];
+    let location = &location[..location.find(bracket)?];
+    let (chromosome, position) = location.rsplit_once(':')?;
+    let chromosome = chromosome.trim_start_matches('<').trim_end_matches('>');
+    Some((chromosome.to_string(), position.parse::<u64>().ok()?))
+}
+
+pub fn classify(reference: &str, alternative: &str) -> &'static str {
+    // classify a ref/alt pair.
+    // breakends look like G]17:198982], ]13:123456]T, or G. / .G for single breakends
+    if alternative.contains('[') || alternative.contains(']')
+        || (alternative.len() > 1 && (alternative.starts_with('.') || alternative.ends_with('.'))) {
+        return "breakend";
+    }
+    match alternative {
+        // a deletion upstream spans the position, rather than a structural variant
+        "*" => return "spanning_deletion",
+        "." => return "no_alt",
+        x if x.starts_with('<') => return "symbolic",
+        _ => (),
+    }
+    let (ref_len, alt_len) = (reference.len(), alternative.len());
+    if ref_len == alt_len {
+        if ref_len == 1 { "SNV" } else { "MNV" }
+    } else if ref_len < alt_len && alternative.starts_with(reference) {
+        "insertion"
+    } else if ref_len > alt_len && reference.starts_with(alternative) {
+        "deletion"
+    } else {
+        "complex"
+    }
+}
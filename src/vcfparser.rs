@@ -2,30 +2,161 @@ use super::{OutputFormat, error::VcfParserError};
 use std::collections::HashMap;
 use std::str;
 use anyhow::Result;
-use std::io::BufRead;
-use vcf::VCFReader;
+use std::io::{self, BufRead, Read};
+use vcf::{VCFReader, VCFRecord};
 use std::sync::Arc;
 use serde_json;
 use crate::utils;
+use crate::derive::DerivedColumn;
+use crate::pedigree::Pedigree;
+use crate::cohort::CohortStats;
+use crate::derive;
+use crate::variant::Projection;
+use crate::row::Schema;
+use crate::filter::Filter;
+use crate::lookup::Lookups;
+use std::collections::HashSet;
+
+/// value of `--fields` that asks for nested fields to be discovered
+pub const AUTO_FIELDS: &str = "auto";
+/// join key of nested fields when `--fields-join` is not given, unless they are discovered
+pub const DEFAULT_FIELDS_JOIN: &str = "Feature";
+/// column naming the input of each row, when there are several
+pub const SOURCE_FILE_COLUMN: &str = "source_file";
+/// number of records to sample when the header does not describe a nested field
+const SAMPLE_RECORDS: usize = 1000;
+
+/// nested field names with their subfields, if already known (otherwise parsed from the header description)
+type NestedFields = Vec<(String, Option<Vec<String>>)>;
+
+/// Counts the lines read through it, so records can be reported by their line number in the file
+pub struct LineCounter<R> {
+    inner: R,
+    /// lines read so far
+    pub lines: u64,
+}
+
+impl<R: BufRead> LineCounter<R> {
+    pub fn new(inner: R) -> Self {
+        LineCounter { inner, lines: 0 }
+    }
+}
+
+impl<R: BufRead> Read for LineCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.lines += buf[..n].iter().filter(|&&x| x == b'\n').count() as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for LineCounter<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // the buffer is already filled, so this does not read
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.lines += buf[..amt.min(buf.len())].iter().filter(|&&x| x == b'\n').count() as u64;
+        }
+        self.inner.consume(amt);
+    }
+}
+
+/// Samples to output, and the names to output them as
+#[derive(Clone, Default)]
+pub struct SampleOptions {
+    /// samples to keep, in this order. All samples if None
+    pub include: Option<Vec<String>>,
+    /// samples to drop
+    pub exclude: Vec<String>,
+    /// VCF sample name -> output name
+    pub rename: HashMap<String, String>,
+}
+
+/// Optional processing on top of exploding, joining and filtering
+#[derive(Clone, Default)]
+pub struct VcfParserOptions {
+    /// join type and whether to ignore version numbers
+    pub join: utils::JoinOptions,
+    /// columns computed from other columns, in order of evaluation
+    pub derived: Vec<DerivedColumn>,
+    /// families to annotate inheritance models with
+    pub pedigree: Option<Pedigree>,
+    /// gene column to find compound heterozygotes in, which needs a pedigree
+    pub comphet: Option<String>,
+    /// allele statistics computed from the genotypes
+    pub cohort: Option<CohortStats>,
+    /// samples to parse and output
+    pub samples: SampleOptions,
+    /// columns read by a subcommand instead of the tsv output, such as the group of `aggregate`.
+    /// `format.X` stands for the FORMAT field X of every sample
+    pub output_columns: Option<Vec<String>>,
+    /// name of the input, when several are parsed together. It is output in the `source_file` column,
+    /// and selected columns only have to be in one of the inputs
+    pub source_file: Option<String>,
+    /// columns to leave out of the output, which can be globs such as info.gnomAD_*
+    pub exclude_columns: Vec<String>,
+    /// columns to output under another name, as (column, name)
+    pub rename_columns: Vec<(String, String)>,
+    /// output INFO columns without `info.` and the name of their field, where that is unambiguous
+    pub strip_prefix: bool,
+    /// files of values, variants and intervals read by filters, shared with the parsers of the other inputs
+    pub lookups: Lookups,
+}
+
 pub struct VcfParser<T>
 where T: BufRead + Send + Sync,
 {
     /// filter to use for filtering variants
     pub filters: serde_json::Value,
+    /// the filter compiled against the schema
+    pub compiled_filter: Filter,
+    /// ids of every column a row can have
+    pub schema: Schema,
     /// info fields have `info.` prefix, such as info.CSQ, info.VEP
     pub info_fields: Vec<String>,
-    /// fields to join on, such as Feature,Transcript_id. Version numbers will be ignored.
-    pub fields_join: Vec<String>,
+    /// fields to join on, such as Feature,Transcript_id. Each key can be composite, such as Gene+Feature
+    pub fields_join: Vec<Vec<String>>,
+    /// column ids of fields_join
+    pub join_keys: Vec<Vec<usize>>,
+    /// join type and whether to ignore version numbers
+    pub join_options: utils::JoinOptions,
     /// output format, tsv, json, vcf(coming soon)
     pub output_format: OutputFormat,
     /// reader to read from
-    pub reader: VCFReader<T>,
+    pub reader: VCFReader<LineCounter<T>>,
     /// vcf header
     pub header: Arc<vcf::VCFHeader>,
     /// CSQ headers
     pub csq_headers: Arc<HashMap<String, Vec<String>>>,
+    /// columns computed from other columns, in order of evaluation
+    pub derived: Vec<DerivedColumn>,
+    /// families to annotate inheritance models with, in the `inheritance` column
+    pub pedigree: Option<Pedigree>,
+    /// gene column to group variants by for compound heterozygotes, reported in the `comphet_id` column
+    pub comphet: Option<String>,
+    /// allele statistics computed from the genotypes, in the `cohort.*` columns
+    pub cohort: Option<CohortStats>,
+    /// selected samples, by their VCF names. Only their genotypes are parsed
+    pub samples: Vec<Vec<u8>>,
+    /// VCF sample name -> output name, for renamed samples
+    pub sample_names: HashMap<String, String>,
+    /// INFO and FORMAT fields to decode, if not all of them are needed
+    pub projection: Option<Projection>,
     /// tsv headers
     pub tsv_headers: Vec<String>,
+    /// column ids of the tsv headers
+    pub tsv_columns: Vec<usize>,
+    /// lines read ahead while sampling for `--fields auto`, to be processed before the rest of the reader
+    pub sampled_lines: Vec<String>,
+    /// line number of the first line after the header, which is the first of `sampled_lines` if any
+    pub first_line: u64,
+    /// name of the input, for the `source_file` column
+    pub source_file: Option<String>,
+    /// column -> name it is output as, for renamed columns
+    pub output_names: HashMap<String, String>,
 }
 impl <T> VcfParser <T>
 where T: BufRead + Send + Sync,
@@ -33,47 +164,262 @@ where T: BufRead + Send + Sync,
     pub fn new(
         filters: serde_json::Value,
         fields: Vec<String>,
-        fields_join: Vec<String>,
+        fields_join: Option<Vec<String>>,
+        options: VcfParserOptions,
         columns: Option<Vec<String>>,
         output_format: OutputFormat,
         reader: T,
     ) -> Result<Self> {
-        if fields.len() >1 && fields.len() != fields_join.len() {
+        let mut reader = VCFReader::new(LineCounter::new(reader))?;
+        let first_line = reader.reader.lines + 1;
+        let header = Arc::new(reader.header().to_owned());
+        let mut sampled_lines: Vec<String> = Vec::new();
+        let (fields, fields_join) = if fields.len() == 1 && fields[0] == AUTO_FIELDS {
+            let nested = discover_nested_fields(&header, &mut reader.reader, first_line, &mut sampled_lines)?;
+            resolve_auto_fields(nested, fields_join)?
+        } else {
+            let fields_join = fields_join.unwrap_or_else(|| vec![DEFAULT_FIELDS_JOIN.to_string()]);
+            (fields.into_iter().map(|x| (x, None)).collect(), fields_join)
+        };
+        if fields.len() != fields_join.len() {
             return Err(VcfParserError::InvalidArgument("Number of fields should be equal to the number of fields_join".into()).into());
         }
-        let info_fields = fields.iter().map(|x| format!("info.{}", x)).collect::<Vec<String>>();
-        let fields_join = fields_join.iter().enumerate().map(|(ind, x)| format!("{}.{}", info_fields[ind], x)).collect::<Vec<String>>();
+        let info_fields = fields.iter().map(|(x, _)| format!("info.{}", x)).collect::<Vec<String>>();
+        let fields_join = fields_join.iter().enumerate().map(|(ind, x)| {
+            x.split('+').map(|k| format!("{}.{}", info_fields[ind], k)).collect::<Vec<String>>()
+        }).collect::<Vec<Vec<String>>>();
         let mut info_headers: Vec<String> = Vec::new();
         let mut csq_headers: HashMap<String, Vec<String>> = HashMap::new();
-        let reader = VCFReader::new(reader)?;
-        let header = Arc::new(reader.header().to_owned());
         for info in header.info_list() {
             let info_str = str::from_utf8(&info)?;
             info_headers.push(info_str.to_string());
             let desc = str::from_utf8(reader.header().info(info).unwrap().description).unwrap();
-            if fields.contains(&info_str.to_string()) {
-                csq_headers.insert(info_str.to_string(), utils::parse_csq_header(desc));
+            match fields.iter().find(|(x, _)| x == info_str) {
+                // subfields discovered by sampling records
+                Some((_, Some(subfields))) => {
+                    csq_headers.insert(info_str.to_string(), subfields.clone());
+                },
+                Some((_, None)) => {
+                    csq_headers.insert(info_str.to_string(), utils::parse_csq_header(desc));
+                },
+                None => (),
             }
         }
         let info_headers = Arc::new(info_headers);
         let csq_headers = Arc::new(csq_headers);
-        let tsv_headers = utils::get_output_header(&info_headers, &csq_headers, header.samples(), &columns);
-        // check if fields_join is a subset of tsv_headers
-        for field in &fields_join {
-            if !tsv_headers.contains(field) {
+        let VcfParserOptions { join: join_options, derived, pedigree, comphet, cohort, samples, output_columns, source_file, exclude_columns, rename_columns, strip_prefix, lookups } = options;
+        let SampleOptions { include, exclude, rename: sample_names } = samples;
+        let vcf_samples = header.samples().iter().map(|x| String::from_utf8_lossy(x).to_string()).collect::<Vec<String>>();
+        for sample in include.iter().flatten().chain(exclude.iter()).chain(sample_names.keys()) {
+            if !vcf_samples.contains(sample) {
+                return Err(VcfParserError::InvalidArgument(format!("Sample {} not found in the VCF", sample)).into());
+            }
+        }
+        let samples = include.unwrap_or(vcf_samples)
+            .into_iter()
+            .filter(|x| !exclude.contains(x))
+            .collect::<Vec<String>>();
+        let output_samples = samples.iter()
+            .map(|x| sample_names.get(x).unwrap_or(x).as_bytes().to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        let samples = samples.into_iter().map(|x| x.into_bytes()).collect::<Vec<Vec<u8>>>();
+        if comphet.is_some() && pedigree.is_none() {
+            return Err(VcfParserError::InvalidArgument("compound heterozygotes need a PED file".into()).into());
+        }
+        let derived_names = derived.iter().map(|x| x.name.to_string()).collect::<Vec<String>>();
+        // site-level annotations come before the derived columns, which may use them
+        let mut extra_columns = Vec::new();
+        if let Some(cohort) = &cohort {
+            extra_columns.extend(cohort.columns());
+        }
+        if pedigree.is_some() {
+            extra_columns.push("inheritance".to_string());
+        }
+        if source_file.is_some() {
+            extra_columns.push(SOURCE_FILE_COLUMN.to_string());
+        }
+        extra_columns.extend(derived_names.iter().cloned());
+        // pairs are found after filtering, so derived columns and filters can't use them
+        if comphet.is_some() {
+            extra_columns.push("comphet_id".to_string());
+        }
+        let all_headers = utils::get_output_header(&info_headers, &csq_headers, &output_samples, &[], &None)?;
+        let tsv_headers = utils::get_output_header(&info_headers, &csq_headers, &output_samples, &extra_columns, &None)?;
+        // columns and filters can refer to renamed columns by either name
+        let output_names = utils::output_names(&tsv_headers, &rename_columns, strip_prefix, source_file.is_none())?;
+        let renamed = output_names.iter().map(|(column, name)| (name.to_string(), column.to_string())).collect::<HashMap<String, String>>();
+        let columns = columns.map(|x| x.into_iter().map(|x| renamed.get(&x).cloned().unwrap_or(x)).collect::<Vec<String>>());
+        let exclude_columns = exclude_columns.into_iter().map(|x| renamed.get(&x).cloned().unwrap_or(x)).collect::<Vec<String>>();
+        let mut filters = filters;
+        utils::rename_filter_columns(&mut filters, &renamed);
+        // with other inputs, the columns of this one are the selected columns it has
+        let tsv_headers = utils::select_columns(&tsv_headers, columns.as_deref(), &exclude_columns, source_file.is_none())?;
+        for name in &derived_names {
+            if all_headers.contains(name) || extra_columns.iter().filter(|&x| x == name).count() > 1 {
+                return Err(VcfParserError::InvalidArgument(format!("Derived column {} already exists", name)).into());
+            }
+        }
+        // only decode the fields used by the output, filters, joins and annotations.
+        // json output has every column, so it needs everything
+        let output = match (output_columns, &columns) {
+            (Some(output_columns), _) => Some(output_columns),
+            (None, Some(_)) if output_format == OutputFormat::T => Some(tsv_headers.clone()),
+            _ => None,
+        };
+        let projection = output.map(|mut needed| {
+            needed.extend(utils::filter_columns(&filters));
+            needed.extend(derived.iter().flat_map(|x| derive::columns(&x.expr)));
+            needed.extend(comphet.iter().cloned());
+            needed.extend(info_fields.iter().cloned());
+            let info = header.info_list()
+                .map(|x| String::from_utf8_lossy(x).to_string())
+                .filter(|id| needed.iter().any(|x| x.strip_prefix("info.").is_some_and(|x| x == id || x.starts_with(&format!("{}.", id)))))
+                .collect::<HashSet<String>>();
+            let mut format = needed.iter().filter_map(|x| x.strip_prefix("format.")).map(|x| x.to_string()).collect::<HashSet<String>>();
+            if output_samples.iter().any(|x| needed.iter().any(|y| y.as_bytes() == x.as_slice())) {
+                // sample columns have every FORMAT field
+                format.extend(header.format_list().map(|x| String::from_utf8_lossy(x).to_string()));
+            }
+            Projection { info, format }
+        });
+        // check if fields_join is a subset of all columns, not just the selected ones
+        for field in fields_join.iter().flatten() {
+            if !all_headers.contains(field) {
                 return Err(VcfParserError::InvalidArgument(format!("Field {} not found in the header", field)).into());
             }
         }
+        let schema_samples = output_samples.iter().map(|x| String::from_utf8_lossy(x).to_string()).collect::<Vec<String>>();
+        let schema = Schema::new(&[all_headers, extra_columns].concat(), &csq_headers, &schema_samples);
+        let tsv_columns = tsv_headers.iter().map(|x| schema.id(x).unwrap()).collect::<Vec<usize>>();
+        let join_keys = fields_join.iter()
+            .map(|key| key.iter().map(|x| schema.id(x).unwrap()).collect())
+            .collect::<Vec<Vec<usize>>>();
+        let compiled_filter = Filter::compile(&filters, &schema, &lookups)?;
         Ok(VcfParser {
             filters,
+            compiled_filter,
+            schema,
             info_fields,
             fields_join,
+            join_keys,
+            join_options,
             output_format,
             reader,
             csq_headers,
+            derived,
+            pedigree,
+            comphet,
+            cohort,
+            samples,
+            sample_names,
+            projection,
             tsv_headers,
+            tsv_columns,
             header,
+            sampled_lines,
+            first_line,
+            source_file,
+            output_names,
         })
     }
 
+    /// output `columns`, such as the union of the columns of several inputs. Columns this input doesn't have are empty
+    pub fn set_output_columns(&mut self, columns: &[String]) {
+        self.tsv_columns = columns.iter().map(|x| self.schema.add(x)).collect();
+        self.tsv_headers = columns.to_vec();
+    }
+
+}
+
+/// Parse a line into a record, reusing its buffers. The record keeps sharing its header
+pub fn parse_record(record: &mut VCFRecord, line: &str, line_number: u64) -> Result<(), VcfParserError> {
+    record.parse_bytes(line.as_bytes(), line_number)
+        .map_err(|e| VcfParserError::MalformedRecord { line: line_number, message: e.to_string() })
+}
+
+/// Find INFO fields holding `|`-separated values.
+/// The header is scanned for `Format: x|y|z` descriptions first. Remaining String fields are checked
+/// against up to `SAMPLE_RECORDS` records. The lines read are kept in `sampled_lines` so they still get processed.
+/// Returns the field names with their subfields, in header order.
+fn discover_nested_fields<R: BufRead>(header: &Arc<vcf::VCFHeader>, reader: &mut R, first_line: u64, sampled_lines: &mut Vec<String>) -> Result<Vec<(String, Vec<String>)>> {
+    let mut nested: NestedFields = Vec::new();
+    for info in header.info_list() {
+        let field = header.info(info).unwrap();
+        if *field.value_type != vcf::ValueType::String {
+            continue;
+        }
+        let desc = str::from_utf8(field.description)?;
+        nested.push((str::from_utf8(info)?.to_string(), utils::parse_nested_format(desc)));
+    }
+    if nested.iter().any(|(_, subfields)| subfields.is_none()) {
+        // sample records for the fields without a usable description.
+        // `None` means no value seen yet, `Some(0)` means the field is not nested.
+        let mut separators: HashMap<String, Option<usize>> = nested.iter()
+            .filter(|(_, subfields)| subfields.is_none())
+            .map(|(id, _)| (id.to_string(), None))
+            .collect();
+        let mut line = String::new();
+        let mut vcf_record = VCFRecord::new(header.clone());
+        let mut records = 0;
+        while records < SAMPLE_RECORDS && reader.read_line(&mut line)? > 0 {
+            let record_line = line.trim_end_matches(['\n', '\r']).to_string();
+            line.clear();
+            // blank and comment lines are kept too, so later lines keep their line numbers
+            sampled_lines.push(record_line);
+            let record_line = &sampled_lines[sampled_lines.len() - 1];
+            if record_line.is_empty() || record_line.starts_with('#') {
+                continue;
+            }
+            records += 1;
+            parse_record(&mut vcf_record, record_line, first_line + sampled_lines.len() as u64 - 1)?;
+            for (id, seen) in separators.iter_mut() {
+                if let Some(values) = vcf_record.info(id.as_bytes()) {
+                    for value in values {
+                        let count = value.iter().filter(|&&x| x == b'|').count();
+                        *seen = match seen {
+                            None => Some(count),
+                            Some(n) if *n == count => Some(count),
+                            _ => Some(0),
+                        };
+                    }
+                }
+            }
+        }
+        for (id, subfields) in nested.iter_mut() {
+            if subfields.is_none() {
+                if let Some(Some(n)) = separators.get(id.as_str()) {
+                    if *n > 0 {
+                        // no names available, so subfields are numbered
+                        *subfields = Some((1..=n + 1).map(|x| x.to_string()).collect());
+                    }
+                }
+            }
+        }
+    }
+    Ok(nested.into_iter().filter_map(|(id, subfields)| subfields.map(|s| (id, s))).collect())
+}
+
+/// Turn discovered nested fields into the `(field, subfields)` and join key lists used by `VcfParser::new`.
+/// Without `fields_join`, each field is joined on its transcript-like subfield, and fields without one
+/// are left unparsed if there is more than one field to join.
+fn resolve_auto_fields(nested: Vec<(String, Vec<String>)>, fields_join: Option<Vec<String>>) -> Result<(NestedFields, Vec<String>)> {
+    if let Some(fields_join) = fields_join {
+        return Ok((nested.into_iter().map(|(id, subfields)| (id, Some(subfields))).collect(), fields_join));
+    }
+    if nested.len() < 2 {
+        let fields_join = nested.iter().map(|(_, subfields)| utils::infer_join_key(subfields).unwrap_or_else(|| subfields[0].to_string())).collect();
+        return Ok((nested.into_iter().map(|(id, subfields)| (id, Some(subfields))).collect(), fields_join));
+    }
+    let mut fields = Vec::new();
+    let mut joins = Vec::new();
+    for (id, subfields) in nested {
+        match utils::infer_join_key(&subfields) {
+            Some(key) => {
+                joins.push(key);
+                fields.push((id, Some(subfields)));
+            },
+            None => eprintln!("Warning: nested field {} has no transcript-like subfield to join on, skipping it", id),
+        }
+    }
+    Ok((fields, joins))
 }
\ No newline at end of file